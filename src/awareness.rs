@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::wire_format::{read_varint, write_varint};
+
+/// Awareness state of a single client, as in the y-protocols awareness format
+#[derive(Clone, Debug, PartialEq)]
+pub struct AwarenessEntry {
+    pub client_id: u64,
+    pub clock: u64,
+    /// JSON encoded state, `null` once the client is gone
+    pub state: String,
+}
+
+impl AwarenessEntry {
+    pub fn is_removed(&self) -> bool {
+        self.state == "null"
+    }
+}
+
+/// A y-protocols awareness update: a varuint count, followed by the varuint client id, varuint
+/// clock and varstring JSON state of every entry
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AwarenessUpdate {
+    pub entries: Vec<AwarenessEntry>,
+}
+
+impl AwarenessUpdate {
+    pub fn decode(mut bytes: &[u8]) -> anyhow::Result<Self> {
+        let (count, rest) = read_varint(bytes)?;
+        bytes = rest;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let (client_id, rest) = read_varint(bytes)?;
+            let (clock, rest) = read_varint(rest)?;
+            let (len, rest) = read_varint(rest)?;
+            if rest.len() < len {
+                anyhow::bail!("truncated awareness state of client {}", client_id);
+            }
+            let (state, rest) = rest.split_at(len);
+            let state = std::str::from_utf8(state)?.to_string();
+            serde_json::from_str::<Value>(&state)?;
+            entries.push(AwarenessEntry {
+                client_id: client_id as u64,
+                clock: clock as u64,
+                state,
+            });
+            bytes = rest;
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, self.entries.len());
+        for entry in self.entries.iter() {
+            write_varint(&mut bytes, entry.client_id as usize);
+            write_varint(&mut bytes, entry.clock as usize);
+            write_varint(&mut bytes, entry.state.len());
+            bytes.extend_from_slice(entry.state.as_bytes());
+        }
+        bytes
+    }
+}
+
+/// The awareness states of the participants of a room, keyed by publisher id
+///
+/// A participant only ever updates the clients it published itself, and all of them are removed
+/// once the participant leaves the room.
+#[derive(Default)]
+pub struct Awareness {
+    participants: HashMap<String, HashMap<u64, AwarenessEntry>>,
+    /// The participant each client belongs to
+    owners: HashMap<u64, String>,
+}
+
+impl Awareness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply an update published by a participant, entries older than the known state or of
+    /// clients of other participants are ignored
+    pub fn apply(&mut self, publisher_id: &str, update: &AwarenessUpdate) {
+        let clients = self.participants.entry(publisher_id.to_string()).or_default();
+        for entry in update.entries.iter() {
+            if let Some(owner) = self.owners.get(&entry.client_id) {
+                if owner != publisher_id {
+                    log::warn!(
+                        "ignoring awareness of client {} of {} published by {}",
+                        entry.client_id,
+                        owner,
+                        publisher_id
+                    );
+                    continue;
+                }
+            }
+            if let Some(known) = clients.get(&entry.client_id) {
+                if known.clock >= entry.clock {
+                    continue;
+                }
+            }
+            if entry.is_removed() {
+                clients.remove(&entry.client_id);
+                self.owners.remove(&entry.client_id);
+            } else {
+                clients.insert(entry.client_id, entry.clone());
+                self.owners.insert(entry.client_id, publisher_id.to_string());
+            }
+        }
+        if clients.is_empty() {
+            self.participants.remove(publisher_id);
+        }
+    }
+
+    /// Forget a participant, returns the update removing its clients for everyone else
+    pub fn remove(&mut self, publisher_id: &str) -> Option<AwarenessUpdate> {
+        let clients = self.participants.remove(publisher_id)?;
+        for client_id in clients.keys() {
+            self.owners.remove(client_id);
+        }
+        let entries = clients
+            .into_values()
+            .map(|entry| AwarenessEntry {
+                client_id: entry.client_id,
+                clock: entry.clock + 1,
+                state: "null".to_string(),
+            })
+            .collect();
+        Some(AwarenessUpdate { entries })
+    }
+
+    /// The current state of every client in the room, `None` without any
+    pub fn update(&self) -> Option<AwarenessUpdate> {
+        let mut entries: Vec<AwarenessEntry> = self
+            .participants
+            .values()
+            .flat_map(|clients| clients.values().cloned())
+            .collect();
+        if entries.is_empty() {
+            return None;
+        }
+        entries.sort_by_key(|entry| entry.client_id);
+        Some(AwarenessUpdate { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(client_id: u64, clock: u64, state: &str) -> AwarenessEntry {
+        AwarenessEntry {
+            client_id,
+            clock,
+            state: state.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_awareness_update_roundtrip() {
+        let update = AwarenessUpdate {
+            entries: vec![entry(1, 2, r#"{"cursor":[1,2]}"#), entry(300, 0, "null")],
+        };
+        let bytes = update.encode();
+        assert_eq!(bytes[..4], [2, 1, 2, 16]);
+        assert_eq!(AwarenessUpdate::decode(&bytes).unwrap(), update);
+
+        assert!(AwarenessUpdate::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(AwarenessUpdate::decode(&[1, 1, 1, 1, b'{']).is_err());
+    }
+
+    #[test]
+    fn test_awareness_states() {
+        let mut awareness = Awareness::new();
+        assert_eq!(awareness.update(), None);
+
+        awareness.apply("a", &AwarenessUpdate { entries: vec![entry(1, 1, "{}")] });
+        awareness.apply("b", &AwarenessUpdate { entries: vec![entry(2, 1, "{}")] });
+        // an older state never overwrites a newer one
+        awareness.apply("a", &AwarenessUpdate { entries: vec![entry(1, 0, "{\"x\":1}")] });
+        assert_eq!(
+            awareness.update().unwrap().entries,
+            vec![entry(1, 1, "{}"), entry(2, 1, "{}")]
+        );
+
+        let removed = awareness.remove("a").unwrap();
+        assert_eq!(removed.entries, vec![entry(1, 2, "null")]);
+        assert_eq!(awareness.remove("a"), None);
+
+        awareness.apply("b", &AwarenessUpdate { entries: vec![entry(2, 2, "null")] });
+        assert_eq!(awareness.update(), None);
+    }
+
+    #[test]
+    fn test_awareness_client_owners() {
+        let mut awareness = Awareness::new();
+        awareness.apply("a", &AwarenessUpdate { entries: vec![entry(1, 1, "{}")] });
+
+        // a participant can neither overwrite nor clear the clients of another one
+        awareness.apply("b", &AwarenessUpdate { entries: vec![entry(1, 2, "{\"x\":1}")] });
+        awareness.apply("b", &AwarenessUpdate { entries: vec![entry(1, 3, "null")] });
+        assert_eq!(awareness.update().unwrap().entries, vec![entry(1, 1, "{}")]);
+        assert_eq!(awareness.remove("b"), None);
+
+        // the client is free again once its participant is gone
+        awareness.remove("a").unwrap();
+        awareness.apply("b", &AwarenessUpdate { entries: vec![entry(1, 4, "{}")] });
+        assert_eq!(awareness.update().unwrap().entries, vec![entry(1, 4, "{}")]);
+    }
+}
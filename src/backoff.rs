@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use crate::config::Config;
+
+/// Exponential backoff between the resubscriptions on a failed participant track
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Resubscriptions before the participant is given up
+    pub attempts: u32,
+}
+
+impl Backoff {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            initial: Duration::from_millis(config.resubscribe_backoff),
+            max: Duration::from_millis(config.resubscribe_backoff_max),
+            attempts: config.resubscribe_attempts,
+        }
+    }
+
+    /// Delay before resubscription `attempt`, counting from 1, `None` once the attempts are used
+    /// up. A `jitter` between 0 and 1 spreads the delay over its upper half, so the participants
+    /// of a relay that went away do not all come back at once.
+    pub fn delay(&self, attempt: u32, jitter: f64) -> Option<Duration> {
+        if attempt == 0 || attempt > self.attempts {
+            return None;
+        }
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        let delay = self.initial.saturating_mul(factor).min(self.max);
+        Some(delay.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(attempts: u32) -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            attempts,
+        }
+    }
+
+    #[test]
+    fn test_backoff_exponential() {
+        let backoff = backoff(40);
+        let delays: Vec<Duration> = (1..=6)
+            .map(|attempt| backoff.delay(attempt, 1.0).unwrap())
+            .collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        assert_eq!(backoff.delay(40, 1.0), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            attempts: 5,
+        };
+        assert_eq!(backoff.delay(2, 0.0), Some(Duration::from_secs(1)));
+        assert_eq!(backoff.delay(2, 0.5), Some(Duration::from_millis(1500)));
+        assert_eq!(backoff.delay(2, 2.0), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_backoff_attempts() {
+        assert!(backoff(3).delay(3, 0.0).is_some());
+        assert_eq!(backoff(3).delay(4, 0.0), None);
+        assert_eq!(backoff(0).delay(1, 0.0), None);
+    }
+}
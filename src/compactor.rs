@@ -0,0 +1,41 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::room_store::RoomStore;
+
+/// Periodically merges the update logs of rooms into their snapshots
+pub struct Compactor {
+    store: Arc<dyn RoomStore>,
+    interval: Duration,
+}
+
+impl Compactor {
+    pub fn new(store: Arc<dyn RoomStore>, interval: Duration) -> Self {
+        Self { store, interval }
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            let store = self.store.clone();
+            // compaction decodes whole documents and touches the disk, keep it off the runtime
+            let res = tokio::task::spawn_blocking(move || Self::compact_all(store.as_ref())).await;
+            // a failed pass is retried on the next interval, it must not stop the server
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => log::warn!("failed compacting rooms: {:?}", err),
+                Err(err) => log::warn!("compaction task failed: {:?}", err),
+            }
+        }
+    }
+
+    fn compact_all(store: &dyn RoomStore) -> anyhow::Result<()> {
+        for room_id in store.uncompacted()? {
+            log::debug!("compacting room {}", room_id);
+            if let Err(err) = store.compact(&room_id) {
+                log::warn!("failed compacting room {}: {:?}", room_id, err);
+            }
+        }
+        Ok(())
+    }
+}
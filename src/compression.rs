@@ -0,0 +1,139 @@
+use std::io::{Read, Write};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Marks a packed snapshot in storage, followed by the `Compression` byte and the data
+///
+/// Every snapshot is written with it, uncompressed ones included, so only snapshots stored
+/// before compression was supported are ever read without it.
+const PACKED_MAGIC: &[u8; 4] = b"YZS1";
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Deflate,
+}
+
+impl Compression {
+    pub fn is_none(&self) -> bool {
+        *self == Compression::None
+    }
+
+    pub fn compress(self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Compression::None => data.to_vec(),
+            Compression::Zstd => zstd::encode_all(data, 0)?,
+            Compression::Deflate => {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+        })
+    }
+
+    /// Decompress at most `limit` bytes, data expanding beyond it is an error rather than being
+    /// decompressed in full
+    pub fn decompress(self, data: &[u8], limit: usize) -> anyhow::Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Compression::None => Box::new(data),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(data)?),
+            Compression::Deflate => Box::new(flate2::read::DeflateDecoder::new(data)),
+        };
+        let mut decompressed = Vec::new();
+        reader
+            .take((limit as u64).saturating_add(1))
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() > limit {
+            anyhow::bail!("decompressed data exceeds {} bytes", limit);
+        }
+        Ok(decompressed)
+    }
+
+    /// Compress a snapshot for storage, recording the compression so `unpack` can reverse it
+    pub fn pack(self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut packed = PACKED_MAGIC.to_vec();
+        packed.push(self.to_byte());
+        packed.extend(self.compress(data)?);
+        Ok(packed)
+    }
+
+    /// Reverse `pack`, snapshots stored before they were packed are returned as is, an unpacked
+    /// snapshot larger than `limit` is an error
+    pub fn unpack(data: &[u8], limit: usize) -> anyhow::Result<Vec<u8>> {
+        match data.strip_prefix(PACKED_MAGIC) {
+            Some([compression, data @ ..]) => Self::from_byte(*compression)?.decompress(data, limit),
+            _ => Compression::None.decompress(data, limit),
+        }
+    }
+
+    /// Unpack a stored snapshot, it merges every update of its room, so no packet size limit
+    /// applies to it
+    pub fn unpack_snapshot(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Self::unpack(data, usize::MAX)
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Deflate => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> anyhow::Result<Self> {
+        Ok(match byte {
+            0 => Compression::None,
+            1 => Compression::Zstd,
+            2 => Compression::Deflate,
+            _ => return Err(anyhow::format_err!("unknown compression {}", byte)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_roundtrip() {
+        let data: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        for compression in [Compression::None, Compression::Zstd, Compression::Deflate] {
+            let compressed = compression.compress(&data).unwrap();
+            if !compression.is_none() {
+                assert!(compressed.len() < data.len());
+            }
+            assert_eq!(compression.decompress(&compressed, data.len()).unwrap(), data);
+
+            let packed = compression.pack(&data).unwrap();
+            assert_eq!(packed[..5], [b'Y', b'Z', b'S', b'1', compression.to_byte()]);
+            assert_eq!(Compression::unpack(&packed, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_compression_limit() {
+        let data = vec![0; 1 << 20];
+        for compression in [Compression::None, Compression::Zstd, Compression::Deflate] {
+            let compressed = compression.compress(&data).unwrap();
+            assert!(compression.decompress(&compressed, data.len() - 1).is_err());
+            let packed = compression.pack(&data).unwrap();
+            assert!(Compression::unpack(&packed, 1024).is_err());
+        }
+    }
+
+    #[test]
+    fn test_compression_unpack_raw() {
+        assert_eq!(Compression::unpack(&[1, 2, 3], 3).unwrap(), vec![1, 2, 3]);
+        assert!(Compression::unpack(b"YZS1\x09", 1024).is_err());
+
+        // a snapshot that happens to start like a packed one survives packing
+        let data = b"YZS1\x01data".to_vec();
+        let packed = Compression::None.pack(&data).unwrap();
+        assert_eq!(Compression::unpack(&packed, data.len()).unwrap(), data);
+    }
+}
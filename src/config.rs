@@ -1,6 +1,12 @@
-use clap::Parser;
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Parser, Subcommand, ValueEnum};
 use moq_native::tls;
-use std::net::SocketAddr;
+
+use crate::{
+    compression::Compression, index_packet::IndexFormat, rate_limit::OverflowPolicy,
+    snapshot_policy::RoomSnapshotPolicy,
+};
 
 #[derive(Parser, Clone)]
 pub struct Config {
@@ -19,6 +25,11 @@ pub struct Config {
     #[arg(long, default_value=".")]
     pub index_namespace: String,
 
+    /// Format of the packets published by the index server, `legacy` also accepts unversioned
+    /// packets so existing index servers keep working, `versioned` rejects them
+    #[arg(long, value_enum, default_value_t=IndexFormat::Legacy)]
+    pub index_format: IndexFormat,
+
     /// The persistence server will subscribe on all publishers with this prefix,
     /// and then publish a room for these participants to subscribe on.
     #[arg(long, default_value="room.participant.")]
@@ -31,4 +42,133 @@ pub struct Config {
     /// The track on which to publish/subscribe for room data
     #[arg(long, default_value=".doc")]
     pub track: String,
+
+    /// Interval in milliseconds at which received packets are published as a single object
+    #[arg(long, default_value="16", value_parser = clap::value_parser!(u64).range(1..))]
+    pub tick: u64,
+
+    /// Number of state packets after which the provider starts a new group with a fresh
+    /// snapshot, 0 to disable
+    #[arg(long, default_value="100")]
+    pub snapshot_deltas: usize,
+
+    /// Bytes of updates after which the provider starts a new group with a fresh snapshot,
+    /// 0 to disable
+    #[arg(long, default_value="1048576")]
+    pub snapshot_bytes: usize,
+
+    /// Seconds after which a changed document gets a new group with a fresh snapshot,
+    /// 0 to disable
+    #[arg(long, default_value="60")]
+    pub snapshot_secs: u64,
+
+    /// Override the snapshot limits of a room: `<room_id>=deltas:<n>,bytes:<n>,secs:<n>`
+    #[arg(long)]
+    pub room_snapshot_policy: Vec<RoomSnapshotPolicy>,
+
+    /// Backend in which room documents are persisted
+    #[arg(long, value_enum, default_value_t=StorageBackend::File)]
+    pub storage: StorageBackend,
+
+    /// Database file of the sqlite storage backend
+    #[arg(long, default_value="rooms.sqlite")]
+    pub sqlite_path: PathBuf,
+
+    /// Directory in which the file storage backend persists room documents
+    #[arg(long, default_value="rooms")]
+    pub storage_dir: PathBuf,
+
+    /// Compression of large snapshots, both published and stored
+    #[arg(long, value_enum, default_value_t=Compression::None)]
+    pub compression: Compression,
+
+    /// Size in bytes above which published snapshots are compressed
+    #[arg(long, default_value="65536")]
+    pub compression_threshold: usize,
+
+    /// Size in bytes above which published snapshots are split into chunks, one object each
+    #[arg(long, default_value="262144")]
+    pub snapshot_chunk_size: usize,
+
+    /// Size in bytes above which objects published by a participant are dropped
+    #[arg(long, default_value="1048576")]
+    pub max_object_size: usize,
+
+    /// Size in bytes above which updates published by a participant are dropped, measured once
+    /// decompressed and reassembled from chunks
+    #[arg(long, default_value="16777216")]
+    pub max_packet_size: usize,
+
+    /// Objects per second a participant may publish, 0 is unlimited
+    #[arg(long, default_value="0")]
+    pub rate_limit_objects: f64,
+
+    /// Bytes per second a participant may publish, 0 is unlimited
+    #[arg(long, default_value="0")]
+    pub rate_limit_bytes: f64,
+
+    /// Seconds worth of the rate limits a participant may publish in a single burst
+    #[arg(long, default_value="2")]
+    pub rate_limit_burst: f64,
+
+    /// What happens to objects of a participant over its rate limits
+    #[arg(long, value_enum, default_value_t=OverflowPolicy::Delay)]
+    pub rate_limit_policy: OverflowPolicy,
+
+    /// Resubscriptions on a failed participant track before the participant is dropped until the
+    /// index inserts it again, 0 to never resubscribe
+    #[arg(long, default_value="5")]
+    pub resubscribe_attempts: u32,
+
+    /// Delay in milliseconds before the first resubscription on a failed participant track,
+    /// doubled with every further attempt
+    #[arg(long, default_value="500")]
+    pub resubscribe_backoff: u64,
+
+    /// Longest delay in milliseconds between resubscriptions on a participant track
+    #[arg(long, default_value="30000")]
+    pub resubscribe_backoff_max: u64,
+
+    /// Interval in seconds at which the update logs of rooms are compacted into snapshots
+    #[arg(long, default_value="30")]
+    pub compaction_interval: u64,
+
+    /// Time in seconds after which a deactivated room is dropped from memory
+    #[arg(long, default_value="300")]
+    pub room_ttl: u64,
+
+    /// Estimated size in bytes of all documents kept in memory, above which the least recently
+    /// used inactive rooms are dropped
+    #[arg(long, default_value="536870912")]
+    pub memory_budget: usize,
+
+    /// Interval in seconds at which idle rooms and the memory budget are checked
+    #[arg(long, default_value="10")]
+    pub eviction_interval: u64,
+
+    /// Interval in seconds at which metrics are logged
+    #[arg(long, default_value="60")]
+    pub metrics_interval: u64,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// A snapshot and update log file per room
+    File,
+    /// A single sqlite database for all rooms
+    Sqlite,
+}
+
+/// Inspect the configured storage instead of running the server
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// List all stored rooms
+    List,
+    /// Show the metadata of a stored room
+    Info { room_id: String },
+    /// Delete a stored room
+    Delete { room_id: String },
 }
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use crate::{
+    metrics::{Metrics, METRICS},
+    rooms::Rooms,
+};
+
+/// Periodically drops idle rooms from memory, after flushing them to storage
+///
+/// Rooms are dropped when they have been deactivated for longer than `ttl`, or when the resident
+/// documents exceed the memory `budget`, least recently used first.
+pub struct Evictor {
+    rooms: Rooms,
+    ttl: Duration,
+    budget: usize,
+    interval: Duration,
+}
+
+impl Evictor {
+    pub fn new(rooms: Rooms, ttl: Duration, budget: usize, interval: Duration) -> Self {
+        Self {
+            rooms,
+            ttl,
+            budget,
+            interval,
+        }
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        // listing scans all of storage, so it only runs once, providers count the rooms they
+        // persist for the first time
+        let store = self.rooms.store();
+        match tokio::task::spawn_blocking(move || store.list()).await? {
+            Ok(room_ids) => Metrics::set(&METRICS.persisted_rooms, room_ids.len()),
+            Err(err) => log::warn!("failed listing stored rooms: {:?}", err),
+        }
+
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            let mut evicted = self.rooms.evict_idle(self.ttl).await;
+            evicted.extend(self.rooms.evict_over_budget(self.budget).await);
+
+            let usage = self.rooms.usage().await;
+            Metrics::set(&METRICS.resident_rooms, usage.rooms);
+            Metrics::set(&METRICS.resident_bytes, usage.bytes);
+            Metrics::add(&METRICS.evicted_rooms, evicted.len());
+
+            if !evicted.is_empty() {
+                log::info!("evicted {} rooms", evicted.len());
+            }
+            let store = self.rooms.store();
+            // flush the logs of evicted rooms, so reloading them only reads a single snapshot
+            tokio::task::spawn_blocking(move || {
+                for room_id in evicted {
+                    if let Err(err) = store.compact(&room_id) {
+                        log::warn!("failed flushing room {}: {:?}", room_id, err);
+                    }
+                }
+            })
+            .await?;
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::room_packet::RoomPacket;
+
+/// A queue per participant between the participants of a room and its provider
+///
+/// The receiver takes packets from the participants round-robin, and the state packets of all
+/// participants before any `Other` packet, so a chatty participant can neither starve the others
+/// nor hold back their updates with ephemeral packets. Every participant may queue `capacity`
+/// packets before its sends wait.
+pub fn channel(capacity: usize) -> (FairQueue, FairReceiver) {
+    let shared = Arc::new(Shared {
+        queues: Mutex::new(Queues::default()),
+        readable: Notify::new(),
+        senders: AtomicUsize::new(0),
+    });
+    let queue = FairQueue {
+        handle: Handle::new(shared.clone()),
+        capacity,
+    };
+    (queue, FairReceiver { shared })
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    /// Notifies the receiver of new packets and dropped senders
+    readable: Notify,
+    senders: AtomicUsize,
+}
+
+/// A queued packet holds on to its place in the queue of its participant
+type Queued = (RoomPacket, OwnedSemaphorePermit);
+
+#[derive(Default)]
+struct Queues {
+    /// Participants with queued packets, in the order they are served
+    order: VecDeque<String>,
+    participants: HashMap<String, ParticipantQueue>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct ParticipantQueue {
+    state: VecDeque<Queued>,
+    other: VecDeque<Queued>,
+}
+
+impl Queues {
+    fn push(&mut self, publisher_id: &str, queued: Queued) {
+        if !self.participants.contains_key(publisher_id) {
+            self.order.push_back(publisher_id.to_string());
+        }
+        let queue = self.participants.entry(publisher_id.to_string()).or_default();
+        match queued.0 {
+            RoomPacket::StatePacket(_) => queue.state.push_back(queued),
+            RoomPacket::Awareness(_) | RoomPacket::Other(_) => queue.other.push_back(queued),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Queued> {
+        let position = self
+            .order
+            .iter()
+            .position(|id| !self.participants[id].state.is_empty())
+            .or_else(|| {
+                self.order
+                    .iter()
+                    .position(|id| !self.participants[id].other.is_empty())
+            })?;
+
+        // the participant moves to the back, or leaves the order once its queue is empty
+        let publisher_id = self.order.remove(position).unwrap();
+        let queue = self.participants.get_mut(&publisher_id).unwrap();
+        let queued = match queue.state.pop_front() {
+            Some(queued) => queued,
+            None => queue.other.pop_front().unwrap(),
+        };
+        if queue.state.is_empty() && queue.other.is_empty() {
+            self.participants.remove(&publisher_id);
+        } else {
+            self.order.push_back(publisher_id);
+        }
+        Some(queued)
+    }
+}
+
+/// Counts the live senders, the receiver is done once all of them are dropped
+struct Handle(Arc<Shared>);
+
+impl Handle {
+    fn new(shared: Arc<Shared>) -> Self {
+        shared.senders.fetch_add(1, Ordering::SeqCst);
+        Self(shared)
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Self {
+        Self::new(self.0.clone())
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.0.senders.fetch_sub(1, Ordering::SeqCst);
+        self.0.readable.notify_one();
+    }
+}
+
+/// Hands out a sender per participant
+#[derive(Clone)]
+pub struct FairQueue {
+    handle: Handle,
+    capacity: usize,
+}
+
+impl FairQueue {
+    pub fn sender(&self, publisher_id: &str) -> FairSender {
+        FairSender {
+            handle: self.handle.clone(),
+            publisher_id: publisher_id.to_string(),
+            space: Arc::new(Semaphore::new(self.capacity)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FairSender {
+    handle: Handle,
+    publisher_id: String,
+    space: Arc<Semaphore>,
+}
+
+impl FairSender {
+    /// Queue a packet, waits while the queue of the participant is full
+    pub async fn send(&self, packet: RoomPacket) -> anyhow::Result<()> {
+        let permit = self.space.clone().acquire_owned().await?;
+        {
+            let mut queues = self.handle.0.queues.lock().unwrap();
+            if queues.closed {
+                anyhow::bail!("room of participant {} is closed", self.publisher_id);
+            }
+            queues.push(&self.publisher_id, (packet, permit));
+        }
+        self.handle.0.readable.notify_one();
+        Ok(())
+    }
+}
+
+pub struct FairReceiver {
+    shared: Arc<Shared>,
+}
+
+impl FairReceiver {
+    /// The next packet, `None` once all senders are dropped and every queue is drained
+    pub async fn recv(&mut self) -> Option<RoomPacket> {
+        loop {
+            // checked before the queues, so no packet sent before the last sender dropped is lost
+            let closed = self.shared.senders.load(Ordering::SeqCst) == 0;
+            if let Some((packet, _permit)) = self.shared.queues.lock().unwrap().pop() {
+                return Some(packet);
+            }
+            if closed {
+                return None;
+            }
+            self.shared.readable.notified().await;
+        }
+    }
+}
+
+impl Drop for FairReceiver {
+    fn drop(&mut self) {
+        // dropping the queued packets releases their permits, so waiting senders see the close
+        let mut queues = self.shared.queues.lock().unwrap();
+        queues.closed = true;
+        queues.order.clear();
+        queues.participants.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::room_packet::{DeltaPacket, StatePacket, UpdateEncoding};
+
+    fn delta(byte: u8) -> RoomPacket {
+        RoomPacket::StatePacket(StatePacket::DocDelta(DeltaPacket {
+            update: vec![byte],
+            encoding: UpdateEncoding::V1,
+        }))
+    }
+
+    fn other(value: u64) -> RoomPacket {
+        RoomPacket::Other(serde_json::json!({ "cursor": value }))
+    }
+
+    #[tokio::test]
+    async fn test_fair_queue_round_robin() {
+        let (queue, mut receiver) = channel(16);
+        let chatty = queue.sender("a");
+        let quiet = queue.sender("b");
+        for i in 0..3 {
+            chatty.send(other(i)).await.unwrap();
+        }
+        for i in 0..3 {
+            chatty.send(delta(i)).await.unwrap();
+        }
+        quiet.send(other(10)).await.unwrap();
+        quiet.send(delta(10)).await.unwrap();
+        drop((queue, chatty, quiet));
+
+        let mut received = Vec::new();
+        while let Some(packet) = receiver.recv().await {
+            received.push(packet);
+        }
+        assert_eq!(
+            received,
+            vec![
+                delta(0),
+                delta(10),
+                delta(1),
+                delta(2),
+                other(10),
+                other(0),
+                other(1),
+                other(2),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fair_queue_capacity() {
+        let (queue, mut receiver) = channel(1);
+        let sender = queue.sender("a");
+        sender.send(delta(0)).await.unwrap();
+
+        let blocked = tokio::time::timeout(Duration::from_millis(10), sender.send(delta(1))).await;
+        assert!(blocked.is_err());
+        // other participants have queues of their own
+        queue.sender("b").send(delta(2)).await.unwrap();
+
+        assert_eq!(receiver.recv().await, Some(delta(0)));
+        sender.send(delta(1)).await.unwrap();
+        assert_eq!(receiver.recv().await, Some(delta(2)));
+        assert_eq!(receiver.recv().await, Some(delta(1)));
+
+        drop(receiver);
+        assert!(sender.send(delta(3)).await.is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use yrs::{
+    updates::{decoder::Decode, encoder::Encode},
+    Update,
+};
+
+use crate::room_packet::{RoomPacket, StatePacket};
+
+/// Collects the packets received during a tick, so they can be published as a single object
+///
+/// Consecutive v1 encoded `DocDelta` packets are merged into one update.
+#[derive(Default)]
+pub struct ForwardScheduler {
+    packets: Vec<RoomPacket>,
+}
+
+impl ForwardScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, packet: RoomPacket) -> anyhow::Result<()> {
+        if let (
+            Some(RoomPacket::StatePacket(StatePacket::DocDelta(last))),
+            RoomPacket::StatePacket(StatePacket::DocDelta(next)),
+        ) = (self.packets.last_mut(), &packet)
+        {
+            if last.encoding.is_v1() && next.encoding.is_v1() {
+                last.update = Self::merge(&last.update, &next.update)?;
+                return Ok(());
+            }
+        }
+        self.packets.push(packet);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Take the packets collected since the last call
+    pub fn take(&mut self) -> Vec<RoomPacket> {
+        std::mem::take(&mut self.packets)
+    }
+
+    fn merge(first: &[u8], second: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let updates = vec![Update::decode_v1(first)?, Update::decode_v1(second)?];
+        Ok(Update::merge_updates(updates).encode_v1())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        room_packet::{DeltaPacket, UpdateEncoding},
+        room_store::shape_updates,
+    };
+    use yrs::{Doc, Map, Transact};
+
+    #[test]
+    fn test_forward_scheduler_merges_deltas() {
+        let deltas = shape_updates(2);
+
+        let mut scheduler = ForwardScheduler::new();
+        assert!(scheduler.is_empty());
+        scheduler
+            .push(RoomPacket::Other(serde_json::json!({"cursor": [1, 2]})))
+            .unwrap();
+        for delta in deltas.iter() {
+            let delta = DeltaPacket {
+                update: delta.clone(),
+                encoding: UpdateEncoding::V1,
+            };
+            scheduler
+                .push(RoomPacket::StatePacket(StatePacket::DocDelta(delta)))
+                .unwrap();
+        }
+        scheduler
+            .push(RoomPacket::Other(serde_json::json!({"cursor": [3, 4]})))
+            .unwrap();
+
+        let packets = scheduler.take();
+        assert!(scheduler.is_empty());
+        assert_eq!(packets.len(), 3);
+
+        let RoomPacket::StatePacket(StatePacket::DocDelta(merged)) = &packets[1] else {
+            panic!("expected a merged delta, got {:?}", packets[1]);
+        };
+        let other = Doc::new();
+        other
+            .transact_mut()
+            .apply_update(Update::decode_v1(&merged.update).unwrap());
+        let other_shapes = other.get_or_insert_map("shapes");
+        assert_eq!(other_shapes.len(&other.transact()), 2);
+    }
+}
@@ -1,3 +1,51 @@
+use std::fmt;
+
+use clap::ValueEnum;
+
+/// Version of the index packet format, the first word of every versioned packet
+const VERSION: &str = "v1";
+
+/// Format of the packets published by the index server
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexFormat {
+    /// `v1 <insert|remove|snapshot>` on the first line, followed by one participant id per line
+    Versioned,
+    /// Versioned packets, and the unversioned format: `+<id>`, `-<id>` or newline separated ids
+    #[default]
+    Legacy,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IndexPacketError {
+    MissingHeader,
+    UnsupportedVersion(String),
+    UnknownType(String),
+    /// Inserts and removes carry exactly one participant id
+    ExpectedOneId(usize),
+    /// Not a packet of the legacy format
+    Malformed,
+}
+
+impl fmt::Display for IndexPacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexPacketError::MissingHeader => write!(f, "missing index packet header"),
+            IndexPacketError::UnsupportedVersion(version) => {
+                write!(f, "unsupported index packet version {:?}", version)
+            }
+            IndexPacketError::UnknownType(packet_type) => {
+                write!(f, "unknown index packet type {:?}", packet_type)
+            }
+            IndexPacketError::ExpectedOneId(count) => {
+                write!(f, "expected one participant id, got {}", count)
+            }
+            IndexPacketError::Malformed => write!(f, "malformed legacy index packet"),
+        }
+    }
+}
+
+impl std::error::Error for IndexPacketError {}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum IndexPacket {
     Insert(String),
@@ -5,24 +53,76 @@ pub enum IndexPacket {
     Snapshot(Vec<String>),
 }
 
-impl From<String> for IndexPacket {
-    fn from(value: String) -> Self {
-        if value.len() == 0 {
-            return IndexPacket::Snapshot(vec![]);
+impl IndexPacket {
+    pub fn parse(payload: &str, format: IndexFormat) -> Result<Self, IndexPacketError> {
+        match format {
+            IndexFormat::Legacy if !Self::is_versioned(payload) => Self::parse_legacy(payload),
+            _ => Self::try_from(payload),
+        }
+    }
+
+    /// Parse the unversioned format, anything but an insert, a remove or newline separated ids is
+    /// an error rather than an empty snapshot, which would remove every participant
+    pub fn parse_legacy(value: &str) -> Result<Self, IndexPacketError> {
+        if value.is_empty() {
+            return Ok(IndexPacket::Snapshot(vec![]));
         }
-        if value.contains("\n") {
+        if value.contains('\n') {
             let parts: Vec<String> = value
-                .split("\n")
+                .split('\n')
                 .map(String::from)
                 .filter(|s| !s.is_empty())
                 .collect();
-            return IndexPacket::Snapshot(parts);
+            return Ok(IndexPacket::Snapshot(parts));
+        }
+
+        if let Some(id) = value.strip_prefix('+') {
+            Ok(Self::Insert(id.to_string()))
+        } else if let Some(id) = value.strip_prefix('-') {
+            Ok(Self::Remove(id.to_string()))
+        } else {
+            Err(IndexPacketError::Malformed)
+        }
+    }
+
+    /// Whether the header of the payload names a version, legacy ids never contain a space
+    fn is_versioned(payload: &str) -> bool {
+        let header = payload.lines().next().unwrap_or_default();
+        match header.split_once(' ') {
+            Some((version, _)) => version
+                .strip_prefix('v')
+                .is_some_and(|number| number.parse::<u32>().is_ok()),
+            None => false,
         }
+    }
+}
 
-        match value.split_at(1) {
-            ("+", value) => Self::Insert(value.to_string()),
-            ("-", value) => Self::Remove(value.to_string()),
-            _ => Self::Snapshot(vec![])
+impl TryFrom<&str> for IndexPacket {
+    type Error = IndexPacketError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut lines = value.lines();
+        let header = lines.next().ok_or(IndexPacketError::MissingHeader)?;
+        let (version, packet_type) = header
+            .split_once(' ')
+            .ok_or(IndexPacketError::MissingHeader)?;
+        if version != VERSION {
+            return Err(IndexPacketError::UnsupportedVersion(version.to_string()));
+        }
+
+        let ids: Vec<String> = lines
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect();
+        let single = |mut ids: Vec<String>| match ids.len() {
+            1 => Ok(ids.remove(0)),
+            count => Err(IndexPacketError::ExpectedOneId(count)),
+        };
+        match packet_type {
+            "insert" => Ok(Self::Insert(single(ids)?)),
+            "remove" => Ok(Self::Remove(single(ids)?)),
+            "snapshot" => Ok(Self::Snapshot(ids)),
+            other => Err(IndexPacketError::UnknownType(other.to_string())),
         }
     }
 }
@@ -33,23 +133,23 @@ mod tests {
     #[test]
     fn test_index_packet_parse() {
         assert_eq!(
-            IndexPacket::from(String::from("+a")),
+            IndexPacket::parse_legacy("+a").unwrap(),
             IndexPacket::Insert(String::from("a"))
         );
         assert_eq!(
-            IndexPacket::from(String::from("+")),
+            IndexPacket::parse_legacy("+").unwrap(),
             IndexPacket::Insert(String::from(""))
         );
         assert_eq!(
-            IndexPacket::from(String::from("-b")),
+            IndexPacket::parse_legacy("-b").unwrap(),
             IndexPacket::Remove(String::from("b"))
         );
         assert_eq!(
-            IndexPacket::from(String::from("")),
+            IndexPacket::parse_legacy("").unwrap(),
             IndexPacket::Snapshot(vec![])
         );
         assert_eq!(
-            IndexPacket::from(String::from("a\nb\nc\n")),
+            IndexPacket::parse_legacy("a\nb\nc\n").unwrap(),
             IndexPacket::Snapshot(vec![
                 String::from("a"),
                 String::from("b"),
@@ -57,17 +157,74 @@ mod tests {
             ])
         );
         assert_eq!(
-            IndexPacket::from(String::from("a\n")),
+            IndexPacket::parse_legacy("a\n").unwrap(),
             IndexPacket::Snapshot(vec![String::from("a")])
         );
         assert_eq!(
-            IndexPacket::from(String::from(
+            IndexPacket::parse_legacy(
                 "-avjVnd-ZhAhSiWwcrSBZ\naM7kvaj-Y1LnFZ4krInja\n"
-            )),
+            ).unwrap(),
             IndexPacket::Snapshot(vec![
                 String::from("-avjVnd-ZhAhSiWwcrSBZ"),
                 String::from("aM7kvaj-Y1LnFZ4krInja")
             ])
         );
     }
+
+    #[test]
+    fn test_index_packet_versioned() {
+        assert_eq!(
+            IndexPacket::try_from("v1 insert\n-a"),
+            Ok(IndexPacket::Insert(String::from("-a")))
+        );
+        assert_eq!(
+            IndexPacket::try_from("v1 remove\nb\n"),
+            Ok(IndexPacket::Remove(String::from("b")))
+        );
+        assert_eq!(
+            IndexPacket::try_from("v1 snapshot\n-a\n"),
+            Ok(IndexPacket::Snapshot(vec![String::from("-a")]))
+        );
+        assert_eq!(
+            IndexPacket::try_from("v1 snapshot"),
+            Ok(IndexPacket::Snapshot(vec![]))
+        );
+        assert_eq!(IndexPacket::try_from(""), Err(IndexPacketError::MissingHeader));
+        assert_eq!(IndexPacket::try_from("+a"), Err(IndexPacketError::MissingHeader));
+        assert_eq!(
+            IndexPacket::try_from("v2 insert\na"),
+            Err(IndexPacketError::UnsupportedVersion(String::from("v2")))
+        );
+        assert_eq!(
+            IndexPacket::try_from("v1 rename\na"),
+            Err(IndexPacketError::UnknownType(String::from("rename")))
+        );
+        assert_eq!(
+            IndexPacket::try_from("v1 insert\na\nb"),
+            Err(IndexPacketError::ExpectedOneId(2))
+        );
+    }
+
+    #[test]
+    fn test_index_packet_parse_format() {
+        assert_eq!(
+            IndexPacket::parse("-a", IndexFormat::Legacy),
+            Ok(IndexPacket::Remove(String::from("a")))
+        );
+        assert_eq!(
+            IndexPacket::parse("v1 insert\na", IndexFormat::Legacy),
+            Ok(IndexPacket::Insert(String::from("a")))
+        );
+        assert!(IndexPacket::parse("-a", IndexFormat::Versioned).is_err());
+    }
+
+    #[test]
+    fn test_index_packet_legacy_malformed() {
+        assert_eq!(IndexPacket::parse_legacy("xyz"), Err(IndexPacketError::Malformed));
+        assert_eq!(IndexPacket::parse_legacy("é"), Err(IndexPacketError::Malformed));
+        assert_eq!(
+            IndexPacket::parse("xyz", IndexFormat::Legacy),
+            Err(IndexPacketError::Malformed)
+        );
+    }
 }
@@ -1,18 +1,45 @@
+mod awareness;
+mod backoff;
+mod compactor;
+mod compression;
 mod config;
+mod evictor;
+mod fair_queue;
+mod forward_scheduler;
 mod session;
+mod snapshot_assembler;
+mod snapshot_policy;
 mod room_listener;
 mod room_provider;
 mod index_packet;
+mod metrics;
 mod participant;
+mod rate_limit;
 mod room_announce_pattern;
 mod room_packet;
 mod rooms;
+mod wire_format;
+mod room_store;
+mod sqlite_room_store;
+
+use std::{
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
 
 use anyhow::Context;
 use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
 use moq_native::quic;
 
-use crate::{config::Config, session::Session};
+use crate::{
+    compactor::Compactor,
+    config::{Command, Config},
+    evictor::Evictor,
+    metrics::Metrics,
+    room_store::RoomStore,
+    rooms::Rooms,
+    session::Session,
+};
 use clap::Parser;
 
 #[tokio::main]
@@ -25,13 +52,32 @@ async fn main() -> anyhow::Result<()> {
 
     let config = Config::parse();
 
+    let store = room_store::open(&config)?;
+    if let Some(command) = config.command.clone() {
+        return run_command(store, command);
+    }
+
     let tls = config.tls.load()?;
 
     let quic = quic::Endpoint::new(quic::Config { bind: config.bind, tls })?;
     let mut server = quic.server.context("missing server certificate")?;
 
+    // shared by all sessions, so every relay connection sees the same rooms
+    let rooms = Rooms::new(store.clone());
+
     let mut tasks = FuturesUnordered::new();
 
+    let compactor = Compactor::new(store.clone(), Duration::from_secs(config.compaction_interval));
+    tasks.push(compactor.run().boxed());
+
+    let evictor = Evictor::new(
+        rooms.clone(),
+        Duration::from_secs(config.room_ttl),
+        config.memory_budget,
+        Duration::from_secs(config.eviction_interval),
+    );
+    tasks.push(evictor.run().boxed());
+    tasks.push(Metrics::report(Duration::from_secs(config.metrics_interval)).boxed());
 
     loop {
         tokio::select! {
@@ -40,7 +86,7 @@ async fn main() -> anyhow::Result<()> {
                 let config = config.clone();
 
                 let session = res.context("failed to accept QUIC connection")?;
-                let session = Session::new(session, config);
+                let session = Session::new(session, config, rooms.clone());
 
                 
                 tasks.push(async move {
@@ -51,3 +97,27 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 }
+
+fn run_command(store: Arc<dyn RoomStore>, command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::List => {
+            for room_id in store.list()? {
+                println!("{}", room_id);
+            }
+        }
+        Command::Info { room_id } => {
+            let metadata = store
+                .metadata(&room_id)?
+                .context(format!("room {} not found", room_id))?;
+            let unix = |time: std::time::SystemTime| {
+                time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+            };
+            println!("room_id:    {}", metadata.room_id);
+            println!("created_at: {}", unix(metadata.created_at));
+            println!("updated_at: {}", unix(metadata.updated_at));
+            println!("size:       {}", metadata.size);
+        }
+        Command::Delete { room_id } => store.delete(&room_id)?,
+    }
+    Ok(())
+}
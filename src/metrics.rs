@@ -0,0 +1,83 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Process wide counters and gauges, periodically written to the log
+pub struct Metrics {
+    /// Rooms whose document is loaded in memory
+    pub resident_rooms: AtomicU64,
+    /// Estimated encoded size of all resident documents
+    pub resident_bytes: AtomicU64,
+    /// Rooms in storage
+    pub persisted_rooms: AtomicU64,
+    /// Rooms dropped from memory since startup
+    pub evicted_rooms: AtomicU64,
+    /// Malformed or oversized objects and packets of participants dropped since startup
+    pub rejected_packets: AtomicU64,
+    /// Objects of participants over their rate limits since startup
+    pub rate_limited_objects: AtomicU64,
+    /// Malformed objects of the index server ignored since startup
+    pub invalid_index_packets: AtomicU64,
+    /// Participant tracks waiting on their subscription
+    pub pending_subscriptions: AtomicU64,
+    /// Participant tracks subscribed on
+    pub active_subscriptions: AtomicU64,
+    /// Failed participant tracks waiting to be subscribed on again
+    pub backing_off_subscriptions: AtomicU64,
+    /// Subscriptions on failed participant tracks since startup
+    pub resubscriptions: AtomicU64,
+    /// Participants dropped after failing all resubscriptions since startup
+    pub abandoned_subscriptions: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    resident_rooms: AtomicU64::new(0),
+    resident_bytes: AtomicU64::new(0),
+    persisted_rooms: AtomicU64::new(0),
+    evicted_rooms: AtomicU64::new(0),
+    rejected_packets: AtomicU64::new(0),
+    rate_limited_objects: AtomicU64::new(0),
+    invalid_index_packets: AtomicU64::new(0),
+    pending_subscriptions: AtomicU64::new(0),
+    active_subscriptions: AtomicU64::new(0),
+    backing_off_subscriptions: AtomicU64::new(0),
+    resubscriptions: AtomicU64::new(0),
+    abandoned_subscriptions: AtomicU64::new(0),
+};
+
+impl Metrics {
+    pub fn set(gauge: &AtomicU64, value: usize) {
+        gauge.store(value as u64, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, value: usize) {
+        counter.fetch_add(value as u64, Ordering::Relaxed);
+    }
+
+    pub fn sub(gauge: &AtomicU64, value: usize) {
+        gauge.fetch_sub(value as u64, Ordering::Relaxed);
+    }
+
+    pub async fn report(interval: Duration) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            log::info!(
+                "metrics: resident_rooms={} resident_bytes={} persisted_rooms={} evicted_rooms={} rejected_packets={} rate_limited_objects={} invalid_index_packets={} pending_subscriptions={} active_subscriptions={} backing_off_subscriptions={} resubscriptions={} abandoned_subscriptions={}",
+                METRICS.resident_rooms.load(Ordering::Relaxed),
+                METRICS.resident_bytes.load(Ordering::Relaxed),
+                METRICS.persisted_rooms.load(Ordering::Relaxed),
+                METRICS.evicted_rooms.load(Ordering::Relaxed),
+                METRICS.rejected_packets.load(Ordering::Relaxed),
+                METRICS.rate_limited_objects.load(Ordering::Relaxed),
+                METRICS.invalid_index_packets.load(Ordering::Relaxed),
+                METRICS.pending_subscriptions.load(Ordering::Relaxed),
+                METRICS.active_subscriptions.load(Ordering::Relaxed),
+                METRICS.backing_off_subscriptions.load(Ordering::Relaxed),
+                METRICS.resubscriptions.load(Ordering::Relaxed),
+                METRICS.abandoned_subscriptions.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
@@ -1,51 +1,137 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
 use anyhow::Context;
 use futures::{stream::FuturesUnordered, StreamExt};
 use moq_transport::{
     serve::{ObjectReader, ObjectsReader, TrackReader, TrackReaderMode},
     session::Subscriber,
 };
-use serde_json::Value;
+use yrs::{updates::decoder::Decode, StateVector, Update};
+
+use crate::{
+    awareness::AwarenessUpdate,
+    config::Config,
+    fair_queue::FairSender,
+    metrics::{Metrics, METRICS},
+    rate_limit::{OverflowPolicy, RateLimitExceeded, RateLimiter, RateLimits},
+    room_packet::{RoomPacket, StatePacket, UpdateEncoding},
+    snapshot_assembler::SnapshotAssembler,
+    wire_format::WireFormat,
+};
+
+/// Objects of a participant read and served at once, the next object is only taken from the
+/// track once one of them is done, so a flooding participant is pushed back on rather than
+/// buffered
+const MAX_IN_FLIGHT_OBJECTS: usize = 16;
+
+/// Limits on what a single participant may publish
+#[derive(Clone, Copy, Debug)]
+pub struct ParticipantLimits {
+    /// Objects larger than this are dropped
+    pub max_object_size: usize,
+    /// Updates larger than this, once decompressed and reassembled, are dropped
+    pub max_packet_size: usize,
+    pub rate: RateLimits,
+}
+
+impl ParticipantLimits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_object_size: config.max_object_size,
+            max_packet_size: config.max_packet_size,
+            rate: RateLimits::from_config(config),
+        }
+    }
+}
+
+/// The track of a participant is not an object stream, subscribing on it again changes nothing
+#[derive(Debug)]
+pub struct UnsupportedTrack {
+    pub publisher_id: String,
+}
+
+impl std::fmt::Display for UnsupportedTrack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "participant {} does not publish an object stream, only object streams are implemented for participants",
+            self.publisher_id
+        )
+    }
+}
 
-use crate::room_packet::{RoomPacket, StatePacket};
+impl std::error::Error for UnsupportedTrack {}
 
 pub struct Participant {
     packet_reader: TrackReader,
-    packet_sender: tokio::sync::mpsc::Sender<RoomPacket>,
+    packet_sender: FairSender,
+    publisher_id: String,
+    limits: ParticipantLimits,
+    assembler: Mutex<SnapshotAssembler>,
+    limiter: Mutex<RateLimiter>,
+    /// Objects and packets of this participant dropped as malformed or oversized
+    rejected: AtomicU64,
 }
 
 impl Participant {
     pub fn new(
         packet_reader: TrackReader,
-        packet_sender: tokio::sync::mpsc::Sender<RoomPacket>,
+        packet_sender: FairSender,
+        publisher_id: String,
+        limits: ParticipantLimits,
     ) -> Self {
-        Self { packet_reader, packet_sender }
+        Self {
+            packet_reader,
+            packet_sender,
+            publisher_id,
+            limits,
+            assembler: Mutex::new(SnapshotAssembler::new(limits.max_packet_size)),
+            limiter: Mutex::new(RateLimiter::new(limits.rate)),
+            rejected: AtomicU64::new(0),
+        }
     }
 
-    pub async fn run_recv(self) -> anyhow::Result<()> {
-        match self
+    /// Receive the packets of the participant, `subscribed` is called once its track delivers
+    pub async fn run_recv(self, subscribed: impl FnOnce()) -> anyhow::Result<()> {
+        let mode = self
             .packet_reader
             .mode()
             .await
-            .context("failed to get mode")?
-        {
+            .context("failed to get mode")?;
+        // the track only gets its mode once the first objects of the participant arrive
+        subscribed();
+        match mode {
             TrackReaderMode::Objects(objects) => self.recv_objects(objects).await,
-            _ => Err(anyhow::format_err!(
-                "Only object streams are implemented for participants"
-            )),
+            _ => Err(UnsupportedTrack {
+                publisher_id: self.publisher_id.clone(),
+            }
+            .into()),
         }?;
         Ok(())
     }
 
     async fn recv_objects(self, mut reader: ObjectsReader) -> anyhow::Result<()> {
+        let this = Arc::new(self);
         let mut tasks = FuturesUnordered::new();
         loop {
             tokio::select! {
-                res = reader.next() => match res? {
+                res = reader.next(), if tasks.len() < MAX_IN_FLIGHT_OBJECTS => match res? {
                     Some(object) => {
-                        let sender = self.packet_sender.clone();
+                        let this = this.clone();
                         tasks.push(async move {
-                            if let Err(err) = Self::serve_object(sender, object).await {
-                                log::warn!("Failed serving object: {}", err);
+                            match this.serve_object(object).await {
+                                Err(err) if err.is::<RateLimitExceeded>() => Err(err),
+                                Err(err) => {
+                                    log::warn!("Failed serving object: {}", err);
+                                    Ok(())
+                                }
+                                Ok(()) => Ok(()),
                             }
                         });
                     },
@@ -53,25 +139,143 @@ impl Participant {
                         break;
                     }
                 },
-                _res = tasks.next(), if !tasks.is_empty() => {}
+                res = tasks.next(), if !tasks.is_empty() => {
+                    if let Some(Err(err)) = res {
+                        return Err(err);
+                    }
+                }
             }
         }
         Ok(())
     }
 
-    async fn serve_object(sender: tokio::sync::mpsc::Sender<RoomPacket>, mut object: ObjectReader) -> anyhow::Result<()> {
-        let received = object.read_all().await?;
-        let received = String::from_utf8_lossy(&received).to_string();
+    async fn serve_object(&self, object: ObjectReader) -> anyhow::Result<()> {
+        let Some(received) = self.read_object(object).await? else {
+            return Ok(());
+        };
+        if !self.admit(received.len()).await? {
+            return Ok(());
+        }
 
-        let received_values: Vec<Value> = serde_json::from_str(&received)?;
-        for value in received_values {
-            let packet = serde_json::from_value::<StatePacket>(value.clone());
-            let received: RoomPacket = match packet {
-                Ok(packet) => RoomPacket::StatePacket(packet),
-                Err(_) => RoomPacket::Other(value),
-            };
-            sender.send(received).await?;
+        // participants may publish either wire format on their track
+        let packets = match WireFormat::decode(&received) {
+            Ok(packets) => packets,
+            Err(err) => {
+                self.reject(err.context("malformed object"));
+                return Ok(());
+            }
+        };
+        for packet in packets {
+            match self.validate(packet) {
+                Ok(Some(packet)) => self.packet_sender.send(packet).await?,
+                Ok(None) => {}
+                Err(err) => self.reject(err),
+            }
         }
         Ok(())
     }
+
+    /// Read an object, `None` if it exceeds the object size limit
+    async fn read_object(&self, mut object: ObjectReader) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut received = Vec::new();
+        while let Some(chunk) = object.read().await? {
+            if received.len() + chunk.len() > self.limits.max_object_size {
+                self.reject(anyhow::format_err!(
+                    "object exceeds {} bytes",
+                    self.limits.max_object_size
+                ));
+                return Ok(None);
+            }
+            received.extend_from_slice(&chunk);
+        }
+        Ok(Some(received))
+    }
+
+    /// Count an object of `size` bytes against the rate limits, false if it has to be dropped
+    async fn admit(&self, size: usize) -> anyhow::Result<bool> {
+        let policy = self.limits.rate.policy;
+        let wait = {
+            let now = Instant::now();
+            let mut limiter = self.limiter.lock().unwrap();
+            let wait = limiter.wait(size, now);
+            // delayed objects reserve their tokens now, so concurrent objects queue up behind them
+            if wait.is_zero() || policy == OverflowPolicy::Delay {
+                limiter.take(size, now);
+            }
+            wait
+        };
+        if wait.is_zero() {
+            return Ok(true);
+        }
+
+        Metrics::add(&METRICS.rate_limited_objects, 1);
+        match policy {
+            OverflowPolicy::Drop => {
+                log::warn!("dropping object of participant {} over its rate limits", self.publisher_id);
+                Ok(false)
+            }
+            OverflowPolicy::Delay => {
+                log::debug!("delaying object of participant {} by {:?}", self.publisher_id, wait);
+                tokio::time::sleep(wait).await;
+                Ok(true)
+            }
+            OverflowPolicy::Disconnect => Err(RateLimitExceeded {
+                publisher_id: self.publisher_id.clone(),
+            }
+            .into()),
+        }
+    }
+
+    /// Check a packet before it reaches the room, state packets are returned in the canonical v1
+    /// encoding, `None` while a snapshot is still missing chunks
+    fn validate(&self, packet: RoomPacket) -> anyhow::Result<Option<RoomPacket>> {
+        let mut packet = match packet {
+            RoomPacket::StatePacket(packet) => packet,
+            RoomPacket::Awareness(mut packet) => {
+                // a participant can only ever speak for its own presence
+                packet.publisher_id = self.publisher_id.clone();
+                AwarenessUpdate::decode(&packet.update).context("malformed awareness update")?;
+                return Ok(Some(RoomPacket::Awareness(packet)));
+            }
+            other => return Ok(Some(other)),
+        };
+
+        // the response is addressed by the track the request came from, never by the client
+        if let StatePacket::DocSyncRequest(request) = &mut packet {
+            request.publisher_id = self.publisher_id.clone();
+            StateVector::decode_v1(&request.state_vector).context("malformed state vector")?;
+        }
+        // objects are served concurrently, so chunks may complete their snapshot in any order
+        if let StatePacket::DocSnapshotChunk(chunk) = packet {
+            let Some(snapshot) = self.assembler.lock().unwrap().push(chunk)? else {
+                return Ok(None);
+            };
+            packet = StatePacket::DocSnapshot(snapshot);
+        }
+
+        // decompression stops at the limit, before a compressed update can grow any further
+        let packet = packet.transcode(UpdateEncoding::V1, self.limits.max_packet_size)?;
+        if let Some(update) = packet.update() {
+            if update.len() > self.limits.max_packet_size {
+                anyhow::bail!(
+                    "update of {} bytes exceeds {} bytes",
+                    update.len(),
+                    self.limits.max_packet_size
+                );
+            }
+            Update::decode_v1(update).context("malformed update")?;
+        }
+        Ok(Some(RoomPacket::StatePacket(packet)))
+    }
+
+    fn reject(&self, err: anyhow::Error) {
+        let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+        Metrics::add(&METRICS.rejected_packets, 1);
+        log::warn!(
+            "dropping packet of participant {} ({} dropped): {:#}",
+            self.publisher_id,
+            rejected,
+            err
+        );
+    }
 }
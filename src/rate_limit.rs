@@ -0,0 +1,180 @@
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+
+use crate::config::Config;
+
+/// What happens to an object published over the rate limits
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the object
+    Drop,
+    /// Hold the object back until it is within the limits
+    Delay,
+    /// Stop the subscription on the participant
+    Disconnect,
+}
+
+/// Rate limits of a single participant, a rate of 0 is unlimited
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimits {
+    pub objects_per_sec: f64,
+    pub bytes_per_sec: f64,
+    /// Seconds worth of the rates a participant may publish at once
+    pub burst: f64,
+    pub policy: OverflowPolicy,
+}
+
+impl RateLimits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            objects_per_sec: config.rate_limit_objects,
+            bytes_per_sec: config.rate_limit_bytes,
+            burst: config.rate_limit_burst,
+            policy: config.rate_limit_policy,
+        }
+    }
+}
+
+/// The participant published over its rate limits under the `disconnect` policy
+#[derive(Debug)]
+pub struct RateLimitExceeded {
+    pub publisher_id: String,
+}
+
+impl std::fmt::Display for RateLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "participant {} exceeded its rate limits", self.publisher_id)
+    }
+}
+
+impl std::error::Error for RateLimitExceeded {}
+
+/// Refills `rate` tokens per second, holding at most `capacity`
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = self.updated.max(now);
+    }
+
+    /// Time until `amount` tokens are available, an amount over the capacity only has to wait
+    /// for a full bucket
+    fn wait(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let missing = amount.min(self.capacity) - self.tokens;
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing / self.rate)
+    }
+
+    /// Take `amount` tokens, the bucket goes into debt when it holds fewer
+    fn take(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.tokens -= amount;
+    }
+}
+
+/// Token buckets on the objects and bytes a participant publishes
+pub struct RateLimiter {
+    objects: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        let now = Instant::now();
+        let bucket = |rate: f64| {
+            (rate > 0.0).then(|| TokenBucket::new(rate, (rate * limits.burst).max(1.0), now))
+        };
+        Self {
+            objects: bucket(limits.objects_per_sec),
+            bytes: bucket(limits.bytes_per_sec),
+        }
+    }
+
+    /// Time until an object of `size` bytes is within the limits
+    pub fn wait(&mut self, size: usize, now: Instant) -> Duration {
+        let objects = self.objects.as_mut().map(|bucket| bucket.wait(1.0, now));
+        let bytes = self.bytes.as_mut().map(|bucket| bucket.wait(size as f64, now));
+        objects.max(bytes).unwrap_or_default()
+    }
+
+    /// Count an object of `size` bytes against the limits
+    pub fn take(&mut self, size: usize, now: Instant) {
+        if let Some(bucket) = self.objects.as_mut() {
+            bucket.take(1.0, now);
+        }
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.take(size as f64, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(objects_per_sec: f64, bytes_per_sec: f64) -> RateLimits {
+        RateLimits {
+            objects_per_sec,
+            bytes_per_sec,
+            burst: 2.0,
+            policy: OverflowPolicy::Drop,
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter_objects() {
+        let mut limiter = RateLimiter::new(limits(10.0, 0.0));
+        let now = Instant::now();
+        for _ in 0..20 {
+            assert_eq!(limiter.wait(1 << 20, now), Duration::ZERO);
+            limiter.take(1 << 20, now);
+        }
+        assert_eq!(limiter.wait(1, now), Duration::from_millis(100));
+
+        let later = now + Duration::from_millis(100);
+        assert_eq!(limiter.wait(1, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_rate_limiter_bytes_debt() {
+        let mut limiter = RateLimiter::new(limits(0.0, 100.0));
+        let now = Instant::now();
+
+        // larger than the burst, but allowed on a full bucket
+        assert_eq!(limiter.wait(500, now), Duration::ZERO);
+        limiter.take(500, now);
+        // the debt has to be paid off before the bucket is full again
+        assert_eq!(limiter.wait(500, now), Duration::from_secs(5));
+        assert_eq!(limiter.wait(100, now), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_rate_limiter_unlimited() {
+        let mut limiter = RateLimiter::new(limits(0.0, 0.0));
+        let now = Instant::now();
+        for _ in 0..1000 {
+            limiter.take(1 << 20, now);
+        }
+        assert_eq!(limiter.wait(1 << 20, now), Duration::ZERO);
+    }
+}
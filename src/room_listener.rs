@@ -1,39 +1,135 @@
-use std::{collections::HashSet, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use anyhow::Context;
-use futures::{stream::FuturesUnordered, StreamExt};
+use futures::FutureExt;
 use moq_transport::{
     serve::{self, TrackReader, TrackReaderMode},
     session::Subscriber,
 };
-use tokio::{sync::Mutex, time::sleep};
+use tokio::task::AbortHandle;
 
 use crate::{
-    index_packet::IndexPacket, participant::Participant, room_announce_pattern::RoomAnnouncePattern, room_packet::RoomPacket
+    backoff::Backoff, config::Config, fair_queue::FairQueue, index_packet::{IndexFormat, IndexPacket}, metrics::{Metrics, METRICS}, participant::{Participant, ParticipantLimits, UnsupportedTrack}, rate_limit::RateLimitExceeded, room_announce_pattern::RoomAnnouncePattern
 };
 
+/// How a listener reads the index and subscribes on the participants in it
+#[derive(Clone, Debug)]
+pub struct ListenerOptions {
+    /// The track of every participant
+    pub track: String,
+    pub index_format: IndexFormat,
+    pub limits: ParticipantLimits,
+    /// Resubscriptions on failed participant tracks
+    pub backoff: Backoff,
+}
+
+impl ListenerOptions {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            track: config.track.clone(),
+            index_format: config.index_format,
+            limits: ParticipantLimits::from_config(config),
+            backoff: Backoff::from_config(config),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct RoomListener {
     relay: Subscriber,
-    sender: tokio::sync::mpsc::Sender<RoomPacket>,
+    sender: FairQueue,
     announce: RoomAnnouncePattern,
-    track: String,
-    participants: Arc<Mutex<HashSet<String>>>,
+    options: ListenerOptions,
+    /// Tells the provider which participants left the room
+    left: tokio::sync::mpsc::UnboundedSender<String>,
+    /// Tasks subscribed on the participants in the index, aborted once they leave it
+    participants: Arc<Mutex<HashMap<String, ParticipantTask>>>,
+    /// Numbers the subscriptions on participants
+    generation: Arc<AtomicU64>,
+}
+
+/// Where the subscription on the track of a participant stands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SubscriptionState {
+    /// Waiting on the first objects of the participant
+    Pending,
+    Subscribed,
+    /// Waiting to resubscribe after the subscription failed
+    BackingOff,
+    /// Left the index, or its subscription ended for good and it is not subscribed on again
+    /// until the index drops it
+    Removed,
+}
+
+impl SubscriptionState {
+    fn gauge(self) -> Option<&'static AtomicU64> {
+        match self {
+            SubscriptionState::Pending => Some(&METRICS.pending_subscriptions),
+            SubscriptionState::Subscribed => Some(&METRICS.active_subscriptions),
+            SubscriptionState::BackingOff => Some(&METRICS.backing_off_subscriptions),
+            SubscriptionState::Removed => None,
+        }
+    }
+}
+
+struct ParticipantTask {
+    task: AbortHandle,
+    /// Tells the task apart from the tasks of earlier subscriptions on the same participant
+    generation: u64,
+    state: SubscriptionState,
+}
+
+impl ParticipantTask {
+    fn set_state(&mut self, id: &str, state: SubscriptionState) {
+        if self.state == state {
+            return;
+        }
+        if let Some(gauge) = self.state.gauge() {
+            Metrics::sub(gauge, 1);
+        }
+        if let Some(gauge) = state.gauge() {
+            Metrics::add(gauge, 1);
+        }
+        log::debug!("subscription on participant {}: {:?} -> {:?}", id, self.state, state);
+        self.state = state;
+    }
+}
+
+/// Aborts the participant tasks once the listener stops, they would outlive it otherwise
+struct AbortParticipants(Arc<Mutex<HashMap<String, ParticipantTask>>>);
+
+impl Drop for AbortParticipants {
+    fn drop(&mut self) {
+        for (id, mut participant) in self.0.lock().unwrap().drain() {
+            participant.set_state(&id, SubscriptionState::Removed);
+            participant.task.abort();
+        }
+    }
 }
 
 impl RoomListener {
     pub fn new(
         relay: Subscriber,
-        sender: tokio::sync::mpsc::Sender<RoomPacket>,
+        sender: FairQueue,
         announce: RoomAnnouncePattern,
-        track: String
+        left: tokio::sync::mpsc::UnboundedSender<String>,
+        options: ListenerOptions,
     ) -> Self {
         Self {
             relay,
             sender,
             announce,
-            track,
-            participants: Arc::new(Mutex::new(HashSet::new())),
+            options,
+            left,
+            participants: Arc::new(Mutex::new(HashMap::new())),
+            generation: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -47,6 +143,7 @@ impl RoomListener {
         .produce();
 
         let mut relay = self.relay.clone();
+        let _participants = AbortParticipants(self.participants.clone());
 
         let res = tokio::select! {
             res = relay.subscribe(writer) => res.context(format!("index_server subscribe failed: {}", self.announce.to_index_track())),
@@ -66,97 +163,219 @@ impl RoomListener {
     }
 
     async fn recv_groups(self, mut groups: serve::GroupsReader) -> anyhow::Result<()> {
-        let mut tasks = FuturesUnordered::new();
-
         while let Some(mut group) = groups.next().await? {
-            loop {
-                tokio::select! {
-                    res = group.read_next() => {
-                        if let Some(object) = res? {
-                            let this = self.clone();
-                            let packet = String::from_utf8_lossy(&object).to_string();
-                            let packet = IndexPacket::from(packet);
-                            tasks.push(async move {
-                                if let Err(err) = Self::handle_packet(this, packet).await {
-                                    log::warn!("error while handling index packet: {}", err)
-                                }
-                            });
-                        } else {
-                            // No more groups
-                            break;
-                        }
-                    },
-                    res = tasks.next(), if !tasks.is_empty() => {
-                        res.unwrap();
-                        // TODO: stop if no more participants are left, happens when index-server closes?
-                        if self.participants.lock().await.len() == 0 {
-                            break;
-                        }
-                    }
+            // subscriptions run as tasks of their own, so handling a packet never waits on them
+            while let Some(object) = group.read_next().await? {
+                if let Some(packet) = self.parse_packet(&object) {
+                    self.handle_packet(packet);
                 }
             }
         }
         Ok(())
     }
 
-    async fn handle_packet(mut self, packet: IndexPacket) -> anyhow::Result<()> {
+    /// Malformed index packets are reported and skipped, they must never change the participants
+    fn parse_packet(&self, payload: &[u8]) -> Option<IndexPacket> {
+        let packet = std::str::from_utf8(payload)
+            .map_err(anyhow::Error::from)
+            .and_then(|packet| Ok(IndexPacket::parse(packet, self.options.index_format)?));
+        match packet {
+            Ok(packet) => Some(packet),
+            Err(err) => {
+                Metrics::add(&METRICS.invalid_index_packets, 1);
+                log::warn!(
+                    "ignoring invalid index packet {:?} on {}: {}",
+                    String::from_utf8_lossy(payload),
+                    self.announce.to_index_track(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    fn handle_packet(&self, packet: IndexPacket) {
         match packet {
             IndexPacket::Insert(id) => {
-                self.add_participant(id).await?;
+                self.add_participant(id);
             }
             IndexPacket::Remove(id) => {
-                self.remove_participant(&id).await;
+                self.remove_participant(&id);
             }
             IndexPacket::Snapshot(ids) => {
                 // remove participants
-                let participants = {
-                    self.participants.clone().lock().await.clone()
-                };
+                let participants: Vec<String> =
+                    self.participants.lock().unwrap().keys().cloned().collect();
                 for id in participants.iter() {
-                    if !ids.contains(&id) {
-                        self.remove_participant(&id).await;
+                    if !ids.contains(id) {
+                        self.remove_participant(id);
                     }
                 }
-                let mut tasks = FuturesUnordered::new();
                 // add participants
-                for id in ids.clone() {
-                    let mut this = self.clone();
-                    tasks.push(async move { this.add_participant(id).await });
-                }
-                while let Some(res) = tasks.next().await {
-                    res?;
+                for id in ids {
+                    self.add_participant(id);
                 }
             }
         }
-        Ok(())
     }
 
-    async fn remove_participant(&mut self, id: &String) {
-        self.participants.lock().await.remove(id);
+    fn remove_participant(&self, id: &String) {
+        if let Some(mut participant) = self.participants.lock().unwrap().remove(id) {
+            participant.set_state(id, SubscriptionState::Removed);
+            // dropping the subscription unsubscribes from the track of the participant
+            participant.task.abort();
+            log::info!("unsubscribed from participant {}", id);
+            // the provider only stops serving once the listener is gone
+            let _ = self.left.send(id.clone());
+        }
+    }
+
+    /// Spawn the subscription on a participant, a failing subscription never affects the others
+    fn add_participant(&self, id: String) {
+        let mut participants = self.participants.lock().unwrap();
+        // a removed subscription stays until the index drops the participant
+        if participants.contains_key(&id) {
+            return;
+        }
+
+        // the task only gets to its first transition once the participant is inserted
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        let task = tokio::spawn(self.clone().supervise(id.clone(), generation));
+        let mut participant = ParticipantTask {
+            task: task.abort_handle(),
+            generation,
+            state: SubscriptionState::Removed,
+        };
+        participant.set_state(&id, SubscriptionState::Pending);
+        participants.insert(id, participant);
+    }
+
+    fn state(&self, id: &str, generation: u64) -> Option<SubscriptionState> {
+        let participants = self.participants.lock().unwrap();
+        participants
+            .get(id)
+            .filter(|participant| participant.generation == generation)
+            .map(|participant| participant.state)
+    }
+
+    /// Move the subscription `generation` of a participant to `state`, false once the
+    /// participant was removed from the index
+    ///
+    /// An aborted task only stops at its next await, it must never touch the subscription of a
+    /// participant that was removed and inserted again in the meantime.
+    fn transition(&self, id: &str, generation: u64, state: SubscriptionState) -> bool {
+        let mut participants = self.participants.lock().unwrap();
+        match participants.get_mut(id) {
+            Some(participant) if participant.generation == generation => {
+                participant.set_state(id, state);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether subscribing on a participant again may succeed, a participant that was
+    /// disconnected or publishes an unsupported track stays so
+    fn is_transient(err: &anyhow::Error) -> bool {
+        !err.is::<RateLimitExceeded>() && !err.is::<UnsupportedTrack>()
+    }
+
+    /// Run the subscription on a participant until it ends for good
+    async fn supervise(self, id: String, generation: u64) {
+        // a panicking subscription must not leave the participant pending until it leaves
+        if AssertUnwindSafe(self.resubscribe(&id, generation))
+            .catch_unwind()
+            .await
+            .is_err()
+        {
+            log::error!("subscription on participant {} panicked", id);
+        }
+
+        // the participant stays removed until the index drops it, so a later index snapshot
+        // listing it does not subscribe on it again
+        if self.transition(&id, generation, SubscriptionState::Removed) {
+            let _ = self.left.send(id);
+        }
     }
 
-    async fn add_participant(&mut self, id: String) -> anyhow::Result<()> {
-        if self.participants.lock().await.contains(&id) {
-            return Ok(());
+    /// Subscribe on a participant, and resubscribe with backoff while its subscription fails
+    async fn resubscribe(&self, id: &str, generation: u64) {
+        let mut attempt = 0;
+        loop {
+            let res = self.subscribe_participant(id, generation).await;
+
+            let err = match res {
+                Ok(()) => {
+                    log::info!("subscription on participant {} ended", id);
+                    break;
+                }
+                Err(err) if !Self::is_transient(&err) => {
+                    log::warn!("not resubscribing on participant {}: {:#}", id, err);
+                    break;
+                }
+                Err(err) => err,
+            };
+
+            // a subscription that was established has recovered, it gets all attempts again
+            if self.state(id, generation) == Some(SubscriptionState::Subscribed) {
+                attempt = 0;
+            }
+            attempt += 1;
+            let Some(delay) = self.options.backoff.delay(attempt, rand::random()) else {
+                Metrics::add(&METRICS.abandoned_subscriptions, 1);
+                log::warn!(
+                    "giving up on participant {} after {} resubscriptions: {:#}",
+                    id,
+                    attempt - 1,
+                    err
+                );
+                break;
+            };
+
+            log::warn!(
+                "subscription on participant {} failed, resubscribing in {:?} ({}/{}): {:#}",
+                id,
+                delay,
+                attempt,
+                self.options.backoff.attempts,
+                err
+            );
+            if !self.transition(id, generation, SubscriptionState::BackingOff) {
+                return;
+            }
+            tokio::time::sleep(delay).await;
+            if !self.transition(id, generation, SubscriptionState::Pending) {
+                return;
+            }
+            Metrics::add(&METRICS.resubscriptions, 1);
         }
-        self.participants.lock().await.insert(id.clone());
+    }
 
+    async fn subscribe_participant(&self, id: &str, generation: u64) -> anyhow::Result<()> {
         // use same announcement, but change the id
         let mut announce = self.announce.clone();
-        announce.publisher_id = id;
+        announce.publisher_id = id.to_string();
         
         let (writer, reader) = serve::Track::new(
             announce.to_namespace(),
-            self.track.to_string(),
+            self.options.track.to_string(),
         )
         .produce();
 
         let mut relay = self.relay.clone();
-        let participant = Participant::new(reader, self.sender.clone());
+        let participant = Participant::new(
+            reader,
+            self.sender.sender(&announce.publisher_id),
+            announce.publisher_id,
+            self.options.limits,
+        );
 
+        let subscribed = || {
+            self.transition(id, generation, SubscriptionState::Subscribed);
+        };
         tokio::select! {
-            res = relay.subscribe(writer) => res.context("index_server subscribe failed"),
-            res = participant.run_recv() => res.context("failed receiving index")
+            res = relay.subscribe(writer) => res.context("failed subscribing on participant track"),
+            res = participant.run_recv(subscribed) => res.context("failed receiving participant track")
         }
     }
 }
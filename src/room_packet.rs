@@ -1,31 +1,369 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Debug)]
+use crate::compression::Compression;
+use yrs::{
+    updates::{decoder::Decode, encoder::Encode},
+    Update,
+};
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum RoomPacket {
     /// Packets that have to be stored
     StatePacket(StatePacket),
+    /// Presence of the participants, tracked by the server but never stored
+    Awareness(AwarenessPacket),
     /// Packets that can be forwarded immediately
     Other(Value)
 }
 
+/// A y-protocols awareness update, JSON encoded as
+/// `{"packet_type":"awareness","publisher_id":..,"update":[..]}`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AwarenessPacket {
+    /// Set by the server to the participant the update was received from, empty for the
+    /// awareness of the whole room
+    pub publisher_id: String,
+    pub update: Vec<u8>,
+}
+
+impl AwarenessPacket {
+    const PACKET_TYPE: &'static str = "awareness";
+
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "packet_type": Self::PACKET_TYPE,
+            "publisher_id": self.publisher_id,
+            "update": self.update,
+        })
+    }
+
+    /// `None` if the value is not an awareness packet
+    pub fn from_json(value: &Value) -> Option<Self> {
+        if value.get("packet_type")?.as_str()? != Self::PACKET_TYPE {
+            return None;
+        }
+        let publisher_id = value
+            .get("publisher_id")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let update = serde_json::from_value(value.get("update")?.clone()).ok()?;
+        Some(Self { publisher_id, update })
+    }
+}
+
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(deny_unknown_fields)] // is this necessary here?
 #[serde(tag = "packet_type", rename_all = "snake_case")]
 pub enum StatePacket {
     DocSnapshot(SnapshotPacket),
+    /// Part of a snapshot too large for a single object, see `SnapshotPacket::chunks`
+    DocSnapshotChunk(SnapshotChunkPacket),
     DocDelta(DeltaPacket),
+    /// A participant asks for the updates it misses since its state vector
+    DocSyncRequest(SyncRequestPacket),
+    /// The updates a single participant misses, answering its `DocSyncRequest`
+    DocSyncResponse(SyncResponsePacket),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl StatePacket {
+    /// The yrs update carried by the packet, encoded as `encoding()`
+    ///
+    /// A snapshot chunk only carries part of an update, it has none until reassembled.
+    pub fn update(&self) -> Option<&[u8]> {
+        match self {
+            StatePacket::DocSnapshot(packet) => Some(&packet.update),
+            StatePacket::DocSnapshotChunk(_) => None,
+            StatePacket::DocDelta(packet) => Some(&packet.update),
+            StatePacket::DocSyncRequest(_) => None,
+            StatePacket::DocSyncResponse(packet) => Some(&packet.update),
+        }
+    }
+
+    pub fn encoding(&self) -> UpdateEncoding {
+        match self {
+            StatePacket::DocSnapshot(packet) => packet.encoding,
+            StatePacket::DocSnapshotChunk(packet) => packet.encoding,
+            StatePacket::DocDelta(packet) => packet.encoding,
+            StatePacket::DocSyncRequest(_) => UpdateEncoding::V1,
+            StatePacket::DocSyncResponse(packet) => packet.encoding,
+        }
+    }
+
+    /// Only snapshots are ever compressed
+    pub fn compression(&self) -> Compression {
+        match self {
+            StatePacket::DocSnapshot(packet) => packet.compression,
+            StatePacket::DocSnapshotChunk(packet) => packet.compression,
+            _ => Compression::None,
+        }
+    }
+
+    /// Re-encode the update of the packet as `encoding`, the result is never compressed
+    ///
+    /// Compressed updates are decompressed up to `limit` bytes, so a small object can never
+    /// expand into an arbitrarily large update.
+    pub fn transcode(self, encoding: UpdateEncoding, limit: usize) -> anyhow::Result<Self> {
+        if let StatePacket::DocSnapshotChunk(packet) = &self {
+            anyhow::bail!("chunk of snapshot {} can only be transcoded once reassembled", packet.snapshot_id);
+        }
+        let Some(update) = self.update() else {
+            return Ok(self);
+        };
+        if self.encoding() == encoding && self.compression().is_none() {
+            return Ok(self);
+        }
+        let update = self.compression().decompress(update, limit)?;
+        let update = if self.encoding() == encoding {
+            update
+        } else {
+            encoding.encode(&self.encoding().decode(&update)?)
+        };
+        Ok(match self {
+            StatePacket::DocSnapshot(_) => StatePacket::DocSnapshot(SnapshotPacket {
+                update,
+                encoding,
+                compression: Compression::None,
+            }),
+            StatePacket::DocDelta(_) => StatePacket::DocDelta(DeltaPacket { update, encoding }),
+            packet @ (StatePacket::DocSnapshotChunk(_) | StatePacket::DocSyncRequest(_)) => packet,
+            StatePacket::DocSyncResponse(packet) => StatePacket::DocSyncResponse(SyncResponsePacket {
+                publisher_id: packet.publisher_id,
+                update,
+                encoding,
+            }),
+        })
+    }
+
+    /// Compress the update of an uncompressed snapshot larger than `threshold` bytes
+    pub fn compress(self, compression: Compression, threshold: usize) -> anyhow::Result<Self> {
+        match self {
+            StatePacket::DocSnapshot(packet)
+                if packet.compression.is_none() && packet.update.len() > threshold =>
+            {
+                Ok(StatePacket::DocSnapshot(SnapshotPacket {
+                    update: compression.compress(&packet.update)?,
+                    encoding: packet.encoding,
+                    compression,
+                }))
+            }
+            packet => Ok(packet),
+        }
+    }
+}
+
+/// Encoding of a yrs update, the server stores and applies `V1`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateEncoding {
+    #[default]
+    V1,
+    V2,
+}
+
+impl UpdateEncoding {
+    pub const ALL: [UpdateEncoding; 2] = [UpdateEncoding::V1, UpdateEncoding::V2];
+
+    pub fn is_v1(&self) -> bool {
+        *self == UpdateEncoding::V1
+    }
+
+    pub fn decode(self, update: &[u8]) -> anyhow::Result<Update> {
+        Ok(match self {
+            UpdateEncoding::V1 => Update::decode_v1(update)?,
+            UpdateEncoding::V2 => Update::decode_v2(update)?,
+        })
+    }
+
+    pub fn encode(self, update: &Update) -> Vec<u8> {
+        match self {
+            UpdateEncoding::V1 => update.encode_v1(),
+            UpdateEncoding::V2 => update.encode_v2(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct SnapshotPacket {
-    pub update: Vec<u8>
+    pub update: Vec<u8>,
+    /// Omitted for v1, so clients unaware of v2 keep working
+    #[serde(default, skip_serializing_if = "UpdateEncoding::is_v1")]
+    pub encoding: UpdateEncoding,
+    /// Compression of `update`, omitted when uncompressed
+    #[serde(default, skip_serializing_if = "Compression::is_none")]
+    pub compression: Compression,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl SnapshotPacket {
+    /// Split the update into `count` chunks, their concatenation is the update
+    pub fn chunks(self, snapshot_id: u64, count: u32) -> Vec<SnapshotChunkPacket> {
+        let len = self.update.len();
+        (0..count)
+            .map(|index| {
+                let start = len * index as usize / count as usize;
+                let end = len * (index as usize + 1) / count as usize;
+                SnapshotChunkPacket {
+                    snapshot_id,
+                    index,
+                    count,
+                    update: self.update[start..end].to_vec(),
+                    encoding: self.encoding,
+                    compression: self.compression,
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotChunkPacket {
+    /// Identifies the snapshot among the snapshots of the same publisher
+    pub snapshot_id: u64,
+    pub index: u32,
+    pub count: u32,
+    /// Part of the (compressed) update of the snapshot
+    pub update: Vec<u8>,
+    #[serde(default, skip_serializing_if = "UpdateEncoding::is_v1")]
+    pub encoding: UpdateEncoding,
+    #[serde(default, skip_serializing_if = "Compression::is_none")]
+    pub compression: Compression,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct DeltaPacket {
-    pub update: Vec<u8>
+    pub update: Vec<u8>,
+    #[serde(default, skip_serializing_if = "UpdateEncoding::is_v1")]
+    pub encoding: UpdateEncoding,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SyncRequestPacket {
+    /// v1 encoded `StateVector` of the participant
+    pub state_vector: Vec<u8>,
+    /// Set by the server to the participant the request was received from
+    #[serde(default)]
+    pub publisher_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SyncResponsePacket {
+    /// The participant that sent the request, other participants ignore the response
+    pub publisher_id: String,
+    pub update: Vec<u8>,
+    #[serde(default, skip_serializing_if = "UpdateEncoding::is_v1")]
+    pub encoding: UpdateEncoding,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_store::shape_updates;
+    use yrs::{Doc, Map, Transact};
+
+    #[test]
+    fn test_state_packet_json() {
+        let packet: StatePacket =
+            serde_json::from_str(r#"{"packet_type":"doc_delta","update":[1,2]}"#).unwrap();
+        assert_eq!(packet.encoding(), UpdateEncoding::V1);
+        assert_eq!(
+            serde_json::to_string(&packet).unwrap(),
+            r#"{"packet_type":"doc_delta","update":[1,2]}"#
+        );
+
+        let packet: StatePacket = serde_json::from_str(
+            r#"{"packet_type":"doc_snapshot","update":[1,2],"encoding":"v2"}"#,
+        )
+        .unwrap();
+        assert_eq!(packet.encoding(), UpdateEncoding::V2);
+
+        let packet: StatePacket =
+            serde_json::from_str(r#"{"packet_type":"doc_sync_request","state_vector":[0]}"#)
+                .unwrap();
+        assert_eq!(
+            packet,
+            StatePacket::DocSyncRequest(SyncRequestPacket {
+                state_vector: vec![0],
+                publisher_id: String::new(),
+            })
+        );
+        assert_eq!(packet.update(), None);
+    }
+
+    #[test]
+    fn test_awareness_packet_json() {
+        let packet = AwarenessPacket {
+            publisher_id: "a".to_string(),
+            update: vec![1, 2],
+        };
+        assert_eq!(AwarenessPacket::from_json(&packet.to_json()), Some(packet));
+
+        let value = serde_json::json!({"packet_type": "awareness", "update": [3]});
+        assert_eq!(AwarenessPacket::from_json(&value).unwrap().update, vec![3]);
+        assert_eq!(AwarenessPacket::from_json(&serde_json::json!({"cursor": [1, 2]})), None);
+    }
+
+    #[test]
+    fn test_state_packet_transcode() {
+        let update = shape_updates(1).remove(0);
+        let packet = StatePacket::DocDelta(DeltaPacket {
+            update: update.clone(),
+            encoding: UpdateEncoding::V1,
+        });
+
+        let v2 = packet.clone().transcode(UpdateEncoding::V2, usize::MAX).unwrap();
+        assert_eq!(v2.encoding(), UpdateEncoding::V2);
+        let v1 = v2.transcode(UpdateEncoding::V1, usize::MAX).unwrap();
+        assert_eq!(v1.encoding(), UpdateEncoding::V1);
+
+        let other = Doc::new();
+        other
+            .transact_mut()
+            .apply_update(Update::decode_v1(v1.update().unwrap()).unwrap());
+        let other_shapes = other.get_or_insert_map("shapes");
+        assert_eq!(other_shapes.len(&other.transact()), 1);
+    }
+
+    #[test]
+    fn test_state_packet_compress() {
+        let packet = StatePacket::DocSnapshot(SnapshotPacket {
+            update: vec![0; 100],
+            encoding: UpdateEncoding::V1,
+            compression: Compression::None,
+        });
+        let small = packet.clone().compress(Compression::Zstd, 1000).unwrap();
+        assert_eq!(small, packet);
+
+        let compressed = packet.clone().compress(Compression::Zstd, 10).unwrap();
+        assert_eq!(compressed.compression(), Compression::Zstd);
+        assert!(compressed.update().unwrap().len() < 100);
+        assert!(serde_json::to_string(&compressed).unwrap().contains(r#""compression":"zstd""#));
+        assert!(compressed.clone().transcode(UpdateEncoding::V1, 99).is_err());
+        assert_eq!(compressed.transcode(UpdateEncoding::V1, 100).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_snapshot_packet_chunks() {
+        let snapshot = SnapshotPacket {
+            update: (0..10).collect(),
+            encoding: UpdateEncoding::V2,
+            compression: Compression::None,
+        };
+        let chunks = snapshot.clone().chunks(7, 3);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.snapshot_id == 7 && chunk.count == 3));
+        assert!(chunks.iter().all(|chunk| chunk.encoding == UpdateEncoding::V2));
+        let update: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.update.clone()).collect();
+        assert_eq!(update, snapshot.update);
+
+        let chunk = StatePacket::DocSnapshotChunk(chunks[0].clone());
+        assert_eq!(chunk.update(), None);
+        assert!(chunk.transcode(UpdateEncoding::V1, usize::MAX).is_err());
+    }
 }
@@ -1,42 +1,101 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use anyhow::Context;
 use log::debug;
 use moq_transport::{
-    serve::{self, Object, ObjectsWriter, TrackWriter},
+    serve::{self, Object, ObjectsWriter, TrackWriter, TracksWriter},
     session::Publisher,
 };
+use tokio::{sync::mpsc::UnboundedReceiver, time::MissedTickBehavior};
 use yrs::{
     updates::{decoder::Decode, encoder::Encode},
     ReadTxn, StateVector, Transact, Update,
 };
 
 use crate::{
+    awareness::{Awareness, AwarenessUpdate},
+    compression::Compression,
+    config::Config,
+    fair_queue::FairReceiver,
+    forward_scheduler::ForwardScheduler,
+    metrics::{Metrics, METRICS},
     room_announce_pattern::RoomAnnouncePattern,
-    room_packet::{RoomPacket, SnapshotPacket, StatePacket},
+    room_packet::{
+        AwarenessPacket, RoomPacket, SnapshotPacket, StatePacket, SyncRequestPacket,
+        SyncResponsePacket, UpdateEncoding,
+    },
+    room_store::RoomStore,
     rooms::Room,
+    snapshot_policy::{SnapshotCadence, SnapshotPolicy},
+    wire_format::{TrackFormat, WireFormat},
 };
 
+/// Ephemeral objects are sent after every state object
+const EPHEMERAL_PRIORITY: u64 = u64::MAX;
+/// Ephemeral packets held back between ticks, older packets are dropped first
+const MAX_EPHEMERAL_PACKETS: usize = 256;
+
+/// How a provider batches, snapshots and publishes the packets of its room
+#[derive(Clone, Debug)]
+pub struct ProviderOptions {
+    /// The track the room is published on, other tracks are named after it
+    pub track: String,
+    /// Interval at which received packets are published as a single object
+    pub tick: Duration,
+    pub snapshot_policy: SnapshotPolicy,
+    /// Compression of large snapshots
+    pub compression: Compression,
+    /// Snapshots larger than this are compressed
+    pub compression_threshold: usize,
+    /// Snapshots larger than this are split into chunks
+    pub chunk_size: usize,
+}
+
+impl ProviderOptions {
+    pub fn from_config(config: &Config, room_id: &str) -> Self {
+        Self {
+            track: config.track.clone(),
+            tick: Duration::from_millis(config.tick),
+            snapshot_policy: SnapshotPolicy::from_config(config, room_id),
+            compression: config.compression,
+            compression_threshold: config.compression_threshold,
+            chunk_size: config.snapshot_chunk_size,
+        }
+    }
+}
+
 pub struct RoomProvider {
     room: Room,
     relay_publisher: Publisher,
-    receiver: tokio::sync::mpsc::Receiver<RoomPacket>,
+    receiver: FairReceiver,
+    left: UnboundedReceiver<String>,
     announce: RoomAnnouncePattern,
-    track: String,
+    store: Arc<dyn RoomStore>,
+    options: ProviderOptions,
 }
 
 impl RoomProvider {
     pub fn new(
         room: Room,
         relay_publisher: Publisher,
-        receiver: tokio::sync::mpsc::Receiver<RoomPacket>,
+        receiver: FairReceiver,
+        left: UnboundedReceiver<String>,
         announce: RoomAnnouncePattern,
-        track: String,
+        store: Arc<dyn RoomStore>,
+        options: ProviderOptions,
     ) -> Self {
         Self {
             room,
             relay_publisher,
             receiver,
+            left,
             announce,
-            track,
+            store,
+            options,
         }
     }
 
@@ -47,10 +106,45 @@ impl RoomProvider {
         .produce();
         log::warn!("announcing: {}", self.announce.to_namespace());
 
-        let track = writer.create(&self.track).unwrap();
+        let objects = TrackFormat::all()
+            .into_iter()
+            .map(|format| -> anyhow::Result<_> {
+                let track = writer
+                    .create(&format.track_name(&self.options.track))
+                    .unwrap();
+                Ok((format, track.objects()?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let ephemeral = WireFormat::ALL
+            .into_iter()
+            .map(|wire| {
+                let track = writer
+                    .create(&wire.ephemeral_track_name(&self.options.track))
+                    .unwrap();
+                (wire, track)
+            })
+            .collect();
+        let lanes = ProviderLanes {
+            state: ProviderTrack::new(
+                objects,
+                self.options.compression,
+                self.options.compression_threshold,
+                self.options.chunk_size,
+            ),
+            ephemeral: EphemeralLane::new(ephemeral)?,
+            sync: SyncLane::new(writer, self.options.track.clone()),
+        };
 
         let res = tokio::select! {
-            res = Self::serve_track(self.receiver, track, self.room) => res.context("failed serving"),
+            res = Self::serve_track(
+                self.receiver,
+                self.left,
+                lanes,
+                self.room,
+                self.store,
+                self.announce.room_id.clone(),
+                &self.options,
+            ) => res.context("failed serving"),
             res = self.relay_publisher.announce(reader) => res.context("provider failed to serve track"),
         };
 
@@ -58,102 +152,661 @@ impl RoomProvider {
         res
     }
 
+    /// Packets are batched per tick by a `ForwardScheduler`, and every snapshot starts a new
+    /// group, so late subscribers can start from the latest group instead of replaying every
+    /// object since the room was activated.
+    ///
+    /// Ephemeral packets take a separate lane, so they never hold back the state updates, and sync
+    /// responses one per requesting participant, so no one else downloads them.
     async fn serve_track(
-        mut receiver: tokio::sync::mpsc::Receiver<RoomPacket>,
-        track: TrackWriter,
+        mut receiver: FairReceiver,
+        mut left: UnboundedReceiver<String>,
+        lanes: ProviderLanes,
         room: Room,
+        store: Arc<dyn RoomStore>,
+        room_id: String,
+        options: &ProviderOptions,
     ) -> anyhow::Result<()> {
-        let mut objects = track.objects()?;
-        let group_id = 0; // TODO: update for every snapshot
-        let mut object_id = 0; // TODO: make incremental
-        let mut priority = 0;
-
-        Self::send_initial_snapshot(
-            room.clone(),
-            &mut objects,
-            Object {
-                group_id,
-                object_id,
-                priority,
-            },
-        )
-        .await?;
+        let ProviderLanes {
+            state: mut writer,
+            mut ephemeral,
+            mut sync,
+        } = lanes;
+        let mut scheduler = ForwardScheduler::new();
+        // state packets of the current tick, persisted together before they are applied
+        let mut pending = Vec::new();
+        let mut awareness = Awareness::new();
+        let mut cadence = SnapshotCadence::new(options.snapshot_policy);
+        let mut tick = tokio::time::interval(options.tick);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-        while let Some(packet) = receiver.recv().await {
-            object_id += 1;
-            priority += 1;
+        // restore the persisted drawing first, otherwise a rejoining participant gets an empty canvas
+        room.hydrate(store.clone(), &room_id)
+            .await
+            .context(format!("failed to restore room {}", room_id))?;
 
-            if let RoomPacket::StatePacket(packet) = &packet {
-                Self::apply_update(room.clone(), &packet).await?;
-            }
-            Self::send(
-                &mut objects,
-                packet,
-                Object {
-                    group_id,
-                    object_id,
-                    priority,
+        writer.write(Self::snapshot(&room, &awareness).await)?;
+
+        loop {
+            let deadline = cadence.deadline();
+            tokio::select! {
+                packet = receiver.recv() => {
+                    let Some(packet) = packet else {
+                        break;
+                    };
+                    match packet {
+                        RoomPacket::StatePacket(StatePacket::DocSyncRequest(request)) => {
+                            match Self::sync_response(&room, request).await {
+                                Ok(response) => {
+                                    let publisher_id = response.publisher_id.clone();
+                                    // the awareness of the room goes along, only to this participant
+                                    let mut packets = vec![
+                                        RoomPacket::StatePacket(StatePacket::DocSyncResponse(response)),
+                                    ];
+                                    packets.extend(Self::room_awareness(&awareness));
+                                    sync.write(&publisher_id, &packets)?;
+                                }
+                                Err(err) => log::warn!("dropping invalid sync request: {:#}", err),
+                            }
+                        }
+                        RoomPacket::StatePacket(StatePacket::DocSyncResponse(response)) => {
+                            log::warn!("ignoring sync response published by {}", response.publisher_id);
+                        }
+                        RoomPacket::StatePacket(packet) => {
+                            // participants validate their packets, still a bad update is only
+                            // dropped, it must never take the room down
+                            match Self::prepare_update(packet) {
+                                Ok(packet) => pending.push(packet),
+                                Err(err) => log::warn!("dropping invalid state packet: {:#}", err),
+                            }
+                        }
+                        RoomPacket::Awareness(packet) => {
+                            match AwarenessUpdate::decode(&packet.update) {
+                                Ok(update) => {
+                                    awareness.apply(&packet.publisher_id, &update);
+                                    ephemeral.push(RoomPacket::Awareness(packet));
+                                }
+                                Err(err) => log::warn!("dropping invalid awareness update: {:#}", err),
+                            }
+                        }
+                        other => ephemeral.push(other),
+                    }
+                },
+                Some(publisher_id) = left.recv() => {
+                    sync.remove(&publisher_id);
+                    // the clients of a participant that left never send their own removal
+                    if let Some(update) = awareness.remove(&publisher_id) {
+                        ephemeral.push(RoomPacket::Awareness(AwarenessPacket {
+                            publisher_id,
+                            update: update.encode(),
+                        }));
+                    }
                 },
-            )
-            .await?;
+                _ = tick.tick() => {
+                    Self::commit(&room, &store, &room_id, &mut pending, &mut cadence, &mut scheduler).await?;
+                    if !scheduler.is_empty() {
+                        writer.write(scheduler.take())?;
+                    }
+                    ephemeral.flush()?;
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {},
+            }
+
+            if cadence.is_due() {
+                // the pending packets belong to the old group, the snapshot already contains them
+                if !scheduler.is_empty() {
+                    writer.write(scheduler.take())?;
+                }
+                cadence.reset();
+                writer.next_group();
+                writer.write(Self::snapshot(&room, &awareness).await)?;
+            }
         }
 
+        Self::commit(
+            &room,
+            &store,
+            &room_id,
+            &mut pending,
+            &mut cadence,
+            &mut scheduler,
+        )
+        .await?;
+        if !scheduler.is_empty() {
+            writer.write(scheduler.take())?;
+        }
+        ephemeral.flush()?;
         Ok(())
     }
 
-    async fn send_initial_snapshot(
-        room: Room,
-        writer: &mut ObjectsWriter,
-        object: Object,
+    /// Persist the state packets of a tick with a single append, and only then apply them to the
+    /// room and forward them
+    ///
+    /// A batch that fails to persist is dropped as a whole, so the room never holds state that is
+    /// not in storage.
+    async fn commit(
+        room: &Room,
+        store: &Arc<dyn RoomStore>,
+        room_id: &str,
+        pending: &mut Vec<StatePacket>,
+        cadence: &mut SnapshotCadence,
+        scheduler: &mut ForwardScheduler,
     ) -> anyhow::Result<()> {
+        let packets = std::mem::take(pending);
+        let updates: Vec<Vec<u8>> = packets
+            .iter()
+            .filter_map(StatePacket::update)
+            .map(<[u8]>::to_vec)
+            .collect();
+        if !updates.is_empty() {
+            let (store, id) = (store.clone(), room_id.to_string());
+            // appends are synced to disk, keep them off the runtime
+            let res =
+                tokio::task::spawn_blocking(move || store.append_batch(&id, &updates)).await?;
+            if let Err(err) = res {
+                log::warn!(
+                    "dropping {} state packets, failed to persist room {}: {:#}",
+                    packets.len(),
+                    room_id,
+                    err
+                );
+                return Ok(());
+            }
+            if room.value.lock().await.record_persisted() {
+                Metrics::add(&METRICS.persisted_rooms, 1);
+            }
+        }
+
+        for packet in packets {
+            if let Err(err) = Self::apply_update(room, &packet).await {
+                log::warn!("dropping invalid state packet: {:#}", err);
+                continue;
+            }
+            cadence.record(packet.update().unwrap_or_default().len());
+            scheduler.push(RoomPacket::StatePacket(packet))?;
+        }
+        Ok(())
+    }
+
+    /// The snapshot of the room, followed by its awareness, which new subscribers start from
+    async fn snapshot(room: &Room, awareness: &Awareness) -> Vec<RoomPacket> {
         let room = room.value.lock().await;
         let snapshot = {
             let txn = room.state.transact_mut();
             let update = txn.encode_diff_v1(&StateVector::default());
-            SnapshotPacket { update }
+            SnapshotPacket {
+                update,
+                encoding: UpdateEncoding::V1,
+                compression: Compression::None,
+            }
         };
-        log::info!("sending initial snapshot: {}", snapshot.update.len());
-        Self::send(
-            writer,
-            RoomPacket::StatePacket(StatePacket::DocSnapshot(snapshot)),
-            object,
-        )
-        .await?;
+        log::info!("sending snapshot: {}", snapshot.update.len());
+        let mut packets = vec![RoomPacket::StatePacket(StatePacket::DocSnapshot(snapshot))];
+        packets.extend(Self::room_awareness(awareness));
+        packets
+    }
+
+    /// The current awareness of every participant, `None` while nobody published any
+    fn room_awareness(awareness: &Awareness) -> Option<RoomPacket> {
+        let update = awareness.update()?;
+        Some(RoomPacket::Awareness(AwarenessPacket {
+            publisher_id: String::new(),
+            update: update.encode(),
+        }))
+    }
+
+    /// Answer a sync request with only the updates the participant misses
+    async fn sync_response(
+        room: &Room,
+        request: SyncRequestPacket,
+    ) -> anyhow::Result<SyncResponsePacket> {
+        let state_vector = StateVector::decode_v1(&request.state_vector)?;
+        let room = room.value.lock().await;
+        let update = room.state.transact().encode_diff_v1(&state_vector);
+        log::info!(
+            "sending sync response to {}: {}",
+            request.publisher_id,
+            update.len()
+        );
+        Ok(SyncResponsePacket {
+            publisher_id: request.publisher_id,
+            update,
+            encoding: UpdateEncoding::V1,
+        })
+    }
+
+    /// Check a state packet, returns it in the canonical v1 encoding to be appended to the log in
+    /// storage
+    fn prepare_update(packet: StatePacket) -> anyhow::Result<StatePacket> {
+        // the room and its storage only hold the canonical v1 encoding, participants already
+        // decompressed the update within their packet size limit
+        let packet = packet.transcode(UpdateEncoding::V1, usize::MAX)?;
+        if let Some(update) = packet.update() {
+            Update::decode_v1(update)?;
+        }
+        Ok(packet)
+    }
+
+    /// Apply a prepared state packet to the room
+    async fn apply_update(room: &Room, packet: &StatePacket) -> anyhow::Result<()> {
+        if let Some(update) = packet.update() {
+            let update_len = update.len();
+            let update = Update::decode_v1(update)?;
+            let mut room_state = room.value.lock().await;
+            room_state.state.transact_mut().apply_update(update);
+            room_state.record_update(update_len);
+        }
         Ok(())
     }
+}
+
+/// Where a provider publishes the packets of its room
+struct ProviderLanes {
+    state: ProviderTrack,
+    ephemeral: EphemeralLane,
+    sync: SyncLane,
+}
+
+/// Where the objects of a track are written, so their numbering can be checked without a session
+trait ObjectSink {
+    fn write(&mut self, object: Object, payload: bytes::Bytes) -> anyhow::Result<()>;
+}
+
+impl ObjectSink for ObjectsWriter {
+    fn write(&mut self, object: Object, payload: bytes::Bytes) -> anyhow::Result<()> {
+        Ok(ObjectsWriter::write(self, object, payload)?)
+    }
+}
+
+/// Numbers the objects written to the provider tracks, one track per format
+struct ProviderTrack<W = ObjectsWriter> {
+    objects: Vec<(TrackFormat, W)>,
+    group_id: u64,
+    object_id: u64,
+    priority: u64,
+    compression: Compression,
+    compression_threshold: usize,
+    /// Snapshots larger than this are written as chunks, one object each
+    chunk_size: usize,
+    snapshot_id: u64,
+}
+
+impl<W: ObjectSink> ProviderTrack<W> {
+    fn new(
+        objects: Vec<(TrackFormat, W)>,
+        compression: Compression,
+        compression_threshold: usize,
+        chunk_size: usize,
+    ) -> Self {
+        Self {
+            objects,
+            group_id: 0,
+            object_id: 0,
+            priority: 0,
+            compression,
+            compression_threshold,
+            chunk_size,
+            snapshot_id: 0,
+        }
+    }
+
+    /// Write v1 encoded packets as a single object on every track, transcoding them where the
+    /// track expects another update encoding, and compressing large snapshots
+    ///
+    /// A snapshot larger than the chunk size is split over objects of its own, every track gets
+    /// the same number of chunks so the object ids stay aligned across tracks.
+    fn write(&mut self, packets: Vec<RoomPacket>) -> anyhow::Result<()> {
+        log::info!("forwarding packets {:?}", packets);
+        let encodings = self.encodings();
+        let mut batch: Vec<Vec<RoomPacket>> = vec![Vec::new(); encodings.len()];
+        for packet in packets {
+            let encoded = encodings
+                .iter()
+                .map(|encoding| self.encode(&packet, *encoding))
+                .collect::<anyhow::Result<Vec<_>>>()?;
 
-    async fn send(
-        writer: &mut ObjectsWriter,
-        packet: RoomPacket,
-        object: Object,
+            let count = self.chunk_count(&encoded);
+            if count <= 1 {
+                for (packets, packet) in batch.iter_mut().zip(encoded) {
+                    packets.push(packet);
+                }
+                continue;
+            }
+
+            if batch.iter().any(|packets| !packets.is_empty()) {
+                let batch = std::mem::replace(&mut batch, vec![Vec::new(); encodings.len()]);
+                self.write_object(&encodings, &batch)?;
+            }
+            self.write_chunks(&encodings, encoded, count)?;
+        }
+        if batch.iter().any(|packets| !packets.is_empty()) {
+            self.write_object(&encodings, &batch)?;
+        }
+        Ok(())
+    }
+
+    /// Write the chunks of one snapshot, given per encoding, as `count` objects
+    fn write_chunks(
+        &mut self,
+        encodings: &[UpdateEncoding],
+        snapshots: Vec<RoomPacket>,
+        count: u32,
     ) -> anyhow::Result<()> {
-        log::info!("forwarding packet {:?}", packet);
-        let payload = match packet {
-            RoomPacket::StatePacket(packet) => serde_json::to_vec(&packet),
-            RoomPacket::Other(v) => serde_json::to_vec(&v),
-        }?;
-
-        log::info!("\tsize: {}", payload.len());
-        writer.write(object, bytes::Bytes::from(payload))?;
+        self.snapshot_id += 1;
+        let mut chunks = snapshots
+            .into_iter()
+            .map(|packet| match packet {
+                RoomPacket::StatePacket(StatePacket::DocSnapshot(snapshot)) => {
+                    Ok(snapshot.chunks(self.snapshot_id, count).into_iter())
+                }
+                packet => Err(anyhow::format_err!("cannot split {:?} into chunks", packet)),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        log::info!("sending snapshot {} in {} chunks", self.snapshot_id, count);
+        for _ in 0..count {
+            let batch: Vec<Vec<RoomPacket>> = chunks
+                .iter_mut()
+                .map(|chunks| {
+                    let chunk = chunks.next().unwrap();
+                    vec![RoomPacket::StatePacket(StatePacket::DocSnapshotChunk(
+                        chunk,
+                    ))]
+                })
+                .collect();
+            self.write_object(encodings, &batch)?;
+        }
+        Ok(())
+    }
+
+    /// Write one object on every track, `batch` holds the packets of each of `encodings`
+    fn write_object(
+        &mut self,
+        encodings: &[UpdateEncoding],
+        batch: &[Vec<RoomPacket>],
+    ) -> anyhow::Result<()> {
+        for (format, objects) in self.objects.iter_mut() {
+            let index = encodings
+                .iter()
+                .position(|encoding| *encoding == format.encoding)
+                .unwrap();
+            let payload = format.wire.encode(&batch[index])?;
+            log::info!("\tsize ({:?}): {}", format, payload.len());
+            objects.write(
+                Object {
+                    group_id: self.group_id,
+                    object_id: self.object_id,
+                    priority: self.priority,
+                },
+                bytes::Bytes::from(payload),
+            )?;
+        }
+        self.object_id += 1;
+        self.priority += 1;
         Ok(())
     }
 
-    async fn apply_update(room: Room, packet: &StatePacket) -> anyhow::Result<()> {
-        let room_state = room.value.lock().await;
-        match packet {
-            StatePacket::DocSnapshot(packet) => {
-                room_state
-                    .state
-                    .transact_mut()
-                    .apply_update(Update::decode_v1(&packet.update)?);
+    /// The update encodings of the tracks, each once
+    fn encodings(&self) -> Vec<UpdateEncoding> {
+        let mut encodings = Vec::new();
+        for (format, _) in self.objects.iter() {
+            if !encodings.contains(&format.encoding) {
+                encodings.push(format.encoding);
             }
-            StatePacket::DocDelta(packet) => {
-                room_state
-                    .state
-                    .transact_mut()
-                    .apply_update(Update::decode_v1(&packet.update)?);
+        }
+        encodings
+    }
+
+    fn encode(&self, packet: &RoomPacket, encoding: UpdateEncoding) -> anyhow::Result<RoomPacket> {
+        Ok(match packet {
+            RoomPacket::StatePacket(packet) => RoomPacket::StatePacket(
+                packet
+                    .clone()
+                    // the packets of the room are never compressed before this point
+                    .transcode(encoding, usize::MAX)?
+                    .compress(self.compression, self.compression_threshold)?,
+            ),
+            other => other.clone(),
+        })
+    }
+
+    /// The number of chunks the largest encoding of a snapshot needs, 0 for other packets
+    fn chunk_count(&self, encoded: &[RoomPacket]) -> u32 {
+        let largest = encoded
+            .iter()
+            .filter_map(|packet| match packet {
+                RoomPacket::StatePacket(StatePacket::DocSnapshot(snapshot)) => {
+                    Some(snapshot.update.len())
+                }
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default();
+        largest.div_ceil(self.chunk_size.max(1)) as u32
+    }
+
+    /// Start a new group, its first object should be a snapshot
+    fn next_group(&mut self) {
+        self.group_id += 1;
+        self.object_id = 0;
+    }
+}
+
+/// Publishes ephemeral packets, such as cursor positions, on tracks of their own, one per wire
+/// format
+///
+/// Every object is a group of its own at the lowest priority, so under congestion a relay can
+/// drop it without holding back later objects. Packets piling up between ticks are dropped
+/// oldest first.
+struct EphemeralLane {
+    objects: Vec<(WireFormat, ObjectsWriter)>,
+    pending: VecDeque<RoomPacket>,
+    group_id: u64,
+}
+
+impl EphemeralLane {
+    fn new(tracks: Vec<(WireFormat, TrackWriter)>) -> anyhow::Result<Self> {
+        let objects = tracks
+            .into_iter()
+            .map(|(wire, track)| -> anyhow::Result<_> { Ok((wire, track.objects()?)) })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            objects,
+            pending: VecDeque::new(),
+            group_id: 0,
+        })
+    }
+
+    fn push(&mut self, packet: RoomPacket) {
+        if self.pending.len() >= MAX_EPHEMERAL_PACKETS {
+            debug!("dropping ephemeral packet, {} pending", self.pending.len());
+            self.pending.pop_front();
+        }
+        self.pending.push_back(packet);
+    }
+
+    /// Write the pending packets as a single object on every track
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let packets: Vec<RoomPacket> = self.pending.drain(..).collect();
+        for (wire, objects) in self.objects.iter_mut() {
+            objects.write(
+                Object {
+                    group_id: self.group_id,
+                    object_id: 0,
+                    priority: EPHEMERAL_PRIORITY,
+                },
+                bytes::Bytes::from(wire.encode(&packets)?),
+            )?;
+        }
+        self.group_id += 1;
+        Ok(())
+    }
+}
+
+/// Tracks carrying the sync responses of a single participant, one per wire format
+///
+/// The tracks of a participant are created with its first sync request, and removed once it
+/// leaves. Every response is a group of its own, so a participant subscribing only after its
+/// request still gets the latest one.
+struct SyncLane {
+    tracks: TracksWriter,
+    track: String,
+    objects: HashMap<String, Vec<(WireFormat, ObjectsWriter)>>,
+    group_id: u64,
+}
+
+impl SyncLane {
+    fn new(tracks: TracksWriter, track: String) -> Self {
+        Self {
+            tracks,
+            track,
+            objects: HashMap::new(),
+            group_id: 0,
+        }
+    }
+
+    /// Write the packets as a single object on every track of the participant
+    fn write(&mut self, publisher_id: &str, packets: &[RoomPacket]) -> anyhow::Result<()> {
+        if !self.objects.contains_key(publisher_id) {
+            let mut objects = Vec::new();
+            for wire in WireFormat::ALL {
+                let name = wire.sync_track_name(&self.track, publisher_id);
+                let track = self
+                    .tracks
+                    .create(&name)
+                    .context(format!("sync track {} already exists", name))?;
+                objects.push((wire, track.objects()?));
             }
-        };
+            self.objects.insert(publisher_id.to_string(), objects);
+        }
+        for (wire, objects) in self.objects.get_mut(publisher_id).unwrap() {
+            objects.write(
+                Object {
+                    group_id: self.group_id,
+                    object_id: 0,
+                    priority: 0,
+                },
+                bytes::Bytes::from(wire.encode(packets)?),
+            )?;
+        }
+        self.group_id += 1;
         Ok(())
     }
+
+    fn remove(&mut self, publisher_id: &str) {
+        if self.objects.remove(publisher_id).is_some() {
+            for wire in WireFormat::ALL {
+                self.tracks
+                    .remove(&wire.sync_track_name(&self.track, publisher_id));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        room_packet::DeltaPacket,
+        room_store::{merge_updates, shape_updates},
+    };
+
+    fn track(chunk_size: usize) -> ProviderTrack<Vec<(Object, bytes::Bytes)>> {
+        let objects = TrackFormat::all()
+            .into_iter()
+            .map(|format| (format, Vec::new()))
+            .collect();
+        ProviderTrack::new(objects, Compression::None, usize::MAX, chunk_size)
+    }
+
+    impl ObjectSink for Vec<(Object, bytes::Bytes)> {
+        fn write(&mut self, object: Object, payload: bytes::Bytes) -> anyhow::Result<()> {
+            self.push((object, payload));
+            Ok(())
+        }
+    }
+
+    fn packets() -> (RoomPacket, RoomPacket) {
+        let update = merge_updates(&shape_updates(100)).unwrap();
+        let delta = RoomPacket::StatePacket(StatePacket::DocDelta(DeltaPacket {
+            update: update.clone(),
+            encoding: UpdateEncoding::V1,
+        }));
+        let snapshot = RoomPacket::StatePacket(StatePacket::DocSnapshot(SnapshotPacket {
+            update,
+            encoding: UpdateEncoding::V1,
+            compression: Compression::None,
+        }));
+        (delta, snapshot)
+    }
+
+    /// The group and object ids written to every track
+    fn ids(track: &ProviderTrack<Vec<(Object, bytes::Bytes)>>) -> Vec<Vec<(u64, u64)>> {
+        track
+            .objects
+            .iter()
+            .map(|(_, objects)| {
+                objects
+                    .iter()
+                    .map(|(object, _)| (object.group_id, object.object_id))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_provider_track_chunks() {
+        let (delta, snapshot) = packets();
+        let mut track = track(256);
+        track.write(vec![delta, snapshot]).unwrap();
+
+        let ids = ids(&track);
+        let count = ids[0].len() as u64 - 1;
+        assert!(count > 1);
+        for ids in ids.iter() {
+            assert_eq!(*ids, (0..=count).map(|id| (0, id)).collect::<Vec<_>>());
+        }
+        for (format, objects) in track.objects.iter() {
+            // the delta received before the snapshot is flushed first
+            let packets = WireFormat::decode(&objects[0].1).unwrap();
+            assert!(matches!(
+                packets[..],
+                [RoomPacket::StatePacket(StatePacket::DocDelta(_))]
+            ));
+            for (_, payload) in objects[1..].iter() {
+                let packets = WireFormat::decode(payload).unwrap();
+                assert!(
+                    matches!(
+                        &packets[..],
+                        [RoomPacket::StatePacket(StatePacket::DocSnapshotChunk(chunk))]
+                            if chunk.count as u64 == count && chunk.encoding == format.encoding
+                    ),
+                    "{:?}",
+                    format
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_provider_track_next_group() {
+        let (delta, snapshot) = packets();
+        let mut track = track(usize::MAX);
+        track.write(vec![snapshot.clone()]).unwrap();
+        track.write(vec![delta.clone()]).unwrap();
+        track.next_group();
+        track.write(vec![snapshot, delta]).unwrap();
+
+        for ids in ids(&track) {
+            assert_eq!(ids, vec![(0, 0), (0, 1), (1, 0)]);
+        }
+        let priorities: Vec<u64> = track.objects[0]
+            .1
+            .iter()
+            .map(|(object, _)| object.priority)
+            .collect();
+        assert_eq!(priorities, vec![0, 1, 2]);
+    }
 }
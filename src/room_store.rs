@@ -0,0 +1,451 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use yrs::{updates::decoder::Decode, Doc, ReadTxn, StateVector, Transact, Update};
+
+use crate::{
+    compression::Compression,
+    config::{Config, StorageBackend},
+    sqlite_room_store::SqliteRoomStore,
+};
+
+/// Durable storage for the documents of rooms, keyed by `room_id`
+///
+/// Every room consists of a snapshot and an append-only log of the updates applied since that
+/// snapshot. All updates are v1 encoded, snapshots may be stored compressed but are always
+/// loaded decompressed.
+pub trait RoomStore: Send + Sync {
+    /// Load all stored updates of a room, the snapshot first followed by the logged updates
+    fn load(&self, room_id: &str) -> anyhow::Result<Vec<Vec<u8>>>;
+
+    /// Append an update to the log of a room
+    fn append(&self, room_id: &str, update: &[u8]) -> anyhow::Result<()> {
+        self.append_batch(room_id, &[update.to_vec()])
+    }
+
+    /// Append updates to the log of a room at once, they are durable once this returns
+    fn append_batch(&self, room_id: &str, updates: &[Vec<u8>]) -> anyhow::Result<()>;
+
+    /// Merge the log of a room into its snapshot, and truncate the log
+    fn compact(&self, room_id: &str) -> anyhow::Result<()>;
+
+    /// Ids of the rooms that logged updates since their last compaction
+    fn uncompacted(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Ids of all stored rooms
+    fn list(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Remove the snapshot and log of a room
+    fn delete(&self, room_id: &str) -> anyhow::Result<()>;
+
+    /// Metadata of a stored room, without loading its document
+    fn metadata(&self, room_id: &str) -> anyhow::Result<Option<RoomMetadata>>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomMetadata {
+    pub room_id: String,
+    pub created_at: SystemTime,
+    pub updated_at: SystemTime,
+    /// Size in bytes of the snapshot and the logged updates
+    pub size: u64,
+}
+
+/// Open the storage backend selected in the config
+pub fn open(config: &Config) -> anyhow::Result<Arc<dyn RoomStore>> {
+    Ok(match config.storage {
+        StorageBackend::File => Arc::new(FileRoomStore::new(
+            config.storage_dir.clone(),
+            config.compression,
+        )?),
+        StorageBackend::Sqlite => Arc::new(SqliteRoomStore::open(
+            &config.sqlite_path,
+            config.compression,
+        )?),
+    })
+}
+
+/// Merge v1 encoded updates into a single v1 encoded snapshot
+pub fn merge_updates(updates: &[Vec<u8>]) -> anyhow::Result<Vec<u8>> {
+    let doc = Doc::new();
+    {
+        let mut txn = doc.transact_mut();
+        for update in updates {
+            txn.apply_update(Update::decode_v1(update)?);
+        }
+    }
+    let snapshot = doc.transact().encode_diff_v1(&StateVector::default());
+    Ok(snapshot)
+}
+
+/// V1 encoded updates of a drawing that each add one shape, as participants publish them
+#[cfg(test)]
+pub fn shape_updates(count: usize) -> Vec<Vec<u8>> {
+    use yrs::Map;
+    let doc = Doc::new();
+    let shapes = doc.get_or_insert_map("shapes");
+    (0..count)
+        .map(|i| {
+            let mut txn = doc.transact_mut();
+            let sv = txn.state_vector();
+            shapes.insert(&mut txn, i.to_string(), i.to_string());
+            txn.encode_diff_v1(&sv)
+        })
+        .collect()
+}
+
+/// Stores every room as a snapshot file `<room_id>.ydoc` and a log file `<room_id>.log`
+///
+/// The log is a sequence of frames, each a little endian `u32` length followed by the update.
+/// Every frame is synced to disk before `append` returns.
+pub struct FileRoomStore {
+    dir: PathBuf,
+    /// A lock per room, serializing its appends and compactions so no update is lost while
+    /// truncating its log
+    locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    /// Compression of newly written snapshots
+    compression: Compression,
+}
+
+impl FileRoomStore {
+    pub fn new(dir: PathBuf, compression: Compression) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir)
+            .context(format!("failed to create storage directory {:?}", dir))?;
+        Ok(Self {
+            dir,
+            locks: Mutex::new(HashMap::new()),
+            compression,
+        })
+    }
+
+    fn lock(&self, room_id: &str) -> Arc<Mutex<()>> {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(room_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Drop the lock of a room from the map once no one else holds it, so the map does not keep
+    /// an entry for every room ever compacted
+    fn release(&self, room_id: &str, lock: Arc<Mutex<()>>) {
+        let mut locks = self.locks.lock().unwrap();
+        // locks are only handed out under the map lock, the map and this caller hold the rest
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(room_id);
+        }
+    }
+
+    fn path(&self, room_id: &str, extension: &str) -> anyhow::Result<PathBuf> {
+        Ok(self
+            .dir
+            .join(format!("{}.{}", encode_room_id(room_id)?, extension)))
+    }
+
+    fn read(&self, room_id: &str, extension: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let path = self.path(room_id, extension)?;
+        match fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(format!("failed to read {:?}", path)),
+        }
+    }
+
+    fn load_locked(&self, room_id: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut updates = Vec::new();
+        if let Some(snapshot) = self.read(room_id, "ydoc")? {
+            updates.push(Compression::unpack_snapshot(&snapshot)?);
+        }
+        if let Some(log) = self.read(room_id, "log")? {
+            updates.extend(decode_log(&log, room_id));
+        }
+        Ok(updates)
+    }
+
+    fn compact_locked(&self, room_id: &str) -> anyhow::Result<()> {
+        let path = self.path(room_id, "ydoc")?;
+        let log = self.path(room_id, "log")?;
+        let snapshot = merge_updates(&self.load_locked(room_id)?)?;
+        let snapshot = self.compression.pack(&snapshot)?;
+        // write to a temporary file first, so a crash never leaves a half written snapshot
+        let tmp = self.path(room_id, "ydoc.tmp")?;
+        let mut file = File::create(&tmp).context(format!("failed to create {:?}", tmp))?;
+        file.write_all(&snapshot)
+            .and_then(|_| file.sync_all())
+            .context(format!("failed to write {:?}", tmp))?;
+        fs::rename(&tmp, &path).context(format!("failed to rename {:?}", tmp))?;
+        // a crash before this point only means the log is applied twice, which yrs ignores
+        match fs::remove_file(&log) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).context(format!("failed to truncate {:?}", log))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn delete_locked(&self, room_id: &str) -> anyhow::Result<()> {
+        for extension in ["ydoc", "log"] {
+            let path = self.path(room_id, extension)?;
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    return Err(err).context(format!("failed to remove {:?}", path));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RoomStore for FileRoomStore {
+    fn load(&self, room_id: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+        let lock = self.lock(room_id);
+        let _lock = lock.lock().unwrap();
+        self.load_locked(room_id)
+    }
+
+    fn append_batch(&self, room_id: &str, updates: &[Vec<u8>]) -> anyhow::Result<()> {
+        let path = self.path(room_id, "log")?;
+        let lock = self.lock(room_id);
+        let _lock = lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(format!("failed to open {:?}", path))?;
+        let frames: Vec<u8> = updates
+            .iter()
+            .flat_map(|update| encode_frame(update))
+            .collect();
+        file.write_all(&frames)
+            .context(format!("failed to append to {:?}", path))?;
+        file.sync_data()
+            .context(format!("failed to sync {:?}", path))?;
+        Ok(())
+    }
+
+    fn compact(&self, room_id: &str) -> anyhow::Result<()> {
+        let lock = self.lock(room_id);
+        let res = {
+            let _lock = lock.lock().unwrap();
+            self.compact_locked(room_id)
+        };
+        self.release(room_id, lock);
+        res
+    }
+
+    fn uncompacted(&self) -> anyhow::Result<Vec<String>> {
+        let mut room_ids = Vec::new();
+        for entry in fs::read_dir(&self.dir).context("failed to list storage directory")? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("log") {
+                continue;
+            }
+            let room_id = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(decode_room_id);
+            if let Some(room_id) = room_id {
+                room_ids.push(room_id);
+            }
+        }
+        Ok(room_ids)
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<String>> {
+        let mut room_ids = BTreeSet::new();
+        for entry in fs::read_dir(&self.dir).context("failed to list storage directory")? {
+            let path = entry?.path();
+            if !matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("ydoc" | "log")
+            ) {
+                continue;
+            }
+            let room_id = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(decode_room_id);
+            if let Some(room_id) = room_id {
+                room_ids.insert(room_id);
+            }
+        }
+        Ok(room_ids.into_iter().collect())
+    }
+
+    fn delete(&self, room_id: &str) -> anyhow::Result<()> {
+        let lock = self.lock(room_id);
+        let res = {
+            let _lock = lock.lock().unwrap();
+            self.delete_locked(room_id)
+        };
+        self.release(room_id, lock);
+        res
+    }
+
+    fn metadata(&self, room_id: &str) -> anyhow::Result<Option<RoomMetadata>> {
+        let lock = self.lock(room_id);
+        let _lock = lock.lock().unwrap();
+        let mut metadata: Option<RoomMetadata> = None;
+        for extension in ["ydoc", "log"] {
+            let path = self.path(room_id, extension)?;
+            let file = match fs::metadata(&path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err).context(format!("failed to stat {:?}", path)),
+            };
+            let updated_at = file.modified()?;
+            // not every filesystem records creation times
+            let created_at = file.created().unwrap_or(updated_at);
+            let metadata = metadata.get_or_insert(RoomMetadata {
+                room_id: room_id.to_string(),
+                created_at,
+                updated_at,
+                size: 0,
+            });
+            metadata.created_at = metadata.created_at.min(created_at);
+            metadata.updated_at = metadata.updated_at.max(updated_at);
+            metadata.size += file.len();
+        }
+        Ok(metadata)
+    }
+}
+
+fn encode_frame(update: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + update.len());
+    frame.extend_from_slice(&(update.len() as u32).to_le_bytes());
+    frame.extend_from_slice(update);
+    frame
+}
+
+/// Split a log into its updates, ignoring a trailing frame that was only partially written
+fn decode_log(mut log: &[u8], room_id: &str) -> Vec<Vec<u8>> {
+    let mut updates = Vec::new();
+    while !log.is_empty() {
+        let len = match log.get(0..4) {
+            Some(len) => u32::from_le_bytes(len.try_into().unwrap()) as usize,
+            None => break,
+        };
+        match log.get(4..4 + len) {
+            Some(update) => updates.push(update.to_vec()),
+            None => break,
+        }
+        log = &log[4 + len..];
+    }
+    if !log.is_empty() {
+        log::warn!(
+            "ignoring {} trailing bytes in log of room {}",
+            log.len(),
+            room_id
+        );
+    }
+    updates
+}
+
+/// Room ids end up in file names, so every byte outside a safe subset of characters is
+/// percent-encoded
+fn encode_room_id(room_id: &str) -> anyhow::Result<String> {
+    if room_id.is_empty() {
+        anyhow::bail!("empty room id");
+    }
+    let mut encoded = String::with_capacity(room_id.len());
+    for byte in room_id.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    Ok(encoded)
+}
+
+/// Reverse `encode_room_id`, `None` for file names it never produces
+fn decode_room_id(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_room_id() {
+        assert_eq!(
+            encode_room_id("aM7kvaj-Y1LnFZ4krInja").unwrap(),
+            "aM7kvaj-Y1LnFZ4krInja"
+        );
+        assert_eq!(encode_room_id("room_1").unwrap(), "room_1");
+        assert_eq!(encode_room_id("../etc").unwrap(), "%2E%2E%2Fetc");
+        assert!(encode_room_id("").is_err());
+        for room_id in ["room_1", "../etc", "a%b c", "zeichnung-ü"] {
+            let encoded = encode_room_id(room_id).unwrap();
+            assert_eq!(decode_room_id(&encoded).as_deref(), Some(room_id));
+        }
+        assert_eq!(decode_room_id("a%2"), None);
+    }
+
+    #[test]
+    fn test_decode_log() {
+        let mut log = encode_frame(&[1, 2, 3]);
+        log.extend(encode_frame(&[]));
+        log.extend(encode_frame(&[4]));
+        assert_eq!(decode_log(&log, "x"), vec![vec![1, 2, 3], vec![], vec![4]]);
+
+        // partially written frame
+        log.extend(&encode_frame(&[5, 6])[..5]);
+        assert_eq!(decode_log(&log, "x"), vec![vec![1, 2, 3], vec![], vec![4]]);
+    }
+
+    #[test]
+    fn test_file_room_store() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let store = FileRoomStore::new(dir.clone(), Compression::Zstd).unwrap();
+
+        let updates = shape_updates(3);
+
+        assert!(store.load("x").unwrap().is_empty());
+        for update in updates.iter() {
+            store.append("x", update).unwrap();
+        }
+        assert_eq!(store.load("x").unwrap(), updates);
+        assert_eq!(store.uncompacted().unwrap(), vec!["x".to_string()]);
+
+        store.compact("x").unwrap();
+        // the lock of a room is only kept while it is in use
+        assert!(store.locks.lock().unwrap().is_empty());
+        assert!(store.uncompacted().unwrap().is_empty());
+        let loaded = store.load("x").unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0], merge_updates(&updates).unwrap());
+
+        assert_eq!(store.list().unwrap(), vec!["x".to_string()]);
+        let metadata = store.metadata("x").unwrap().unwrap();
+        let stored = fs::read(dir.join("x.ydoc")).unwrap();
+        assert_eq!(metadata.size, stored.len() as u64);
+        store.delete("x").unwrap();
+        assert!(store.list().unwrap().is_empty());
+        assert_eq!(store.metadata("x").unwrap(), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,45 +1,147 @@
 use std::{
     collections::HashMap,
-    sync::Arc,
+    sync::{self, Arc},
+    time::{Duration, Instant},
 };
 
 use tokio::sync::Mutex;
-use yrs::Doc;
+use yrs::{updates::decoder::Decode, Doc, ReadTxn, StateVector, Transact, Update};
+
+use crate::room_store::RoomStore;
+
+/// Bytes of updates a room records before its size is measured again, at the least
+const MIN_UNMEASURED: usize = 64 * 1024;
 
 pub struct RoomState {
     pub state: Doc,
+    /// When the room was last activated or updated
+    last_used: Instant,
+    /// Estimate of the encoded size of `state` in bytes
+    size: usize,
+    /// Bytes of updates added to `size` since it was last measured
+    unmeasured: usize,
+    /// Whether the persisted state was applied to `state`
+    hydrated: bool,
+    /// Whether the room has any state in storage
+    persisted: bool,
+}
+
+impl RoomState {
+    /// Account for an update of `len` bytes applied to the document
+    pub fn record_update(&mut self, len: usize) {
+        self.last_used = Instant::now();
+        self.size += len;
+        self.unmeasured += len;
+        // merged updates take less than their deltas, so measure again once the estimate doubled
+        if self.unmeasured > (self.size - self.unmeasured).max(MIN_UNMEASURED) {
+            self.measure();
+        }
+    }
+
+    /// Mark the room as persisted, returns false if it already was
+    pub fn record_persisted(&mut self) -> bool {
+        !std::mem::replace(&mut self.persisted, true)
+    }
+
+    fn measure(&mut self) {
+        self.size = self
+            .state
+            .transact()
+            .encode_diff_v1(&StateVector::default())
+            .len();
+        self.unmeasured = 0;
+    }
+}
+
+/// Whether a session serves the room, kept apart from the document so a session dropped while
+/// serving releases the room without awaiting its lock
+#[derive(Default)]
+struct Activation {
     active: bool,
+    /// When the room was last deactivated, `None` while it is active or never was
+    deactivated_at: Option<Instant>,
 }
 
 #[derive(Clone)]
 pub struct Room {
     pub value: Arc<Mutex<RoomState>>,
+    activation: Arc<sync::Mutex<Activation>>,
+}
+
+/// A room activated by a session, deactivated once dropped
+pub struct ActiveRoom {
+    room: Room,
+}
+
+impl Drop for ActiveRoom {
+    fn drop(&mut self) {
+        let mut activation = self.room.activation.lock().unwrap();
+        activation.active = false;
+        activation.deactivated_at = Some(Instant::now());
+    }
 }
 
 impl Room {
     pub fn new() -> Self {
-        return {
-            let doc = Doc::new();
-            doc.get_or_insert_map("shapes");
-            Self {
-                value: Arc::new(Mutex::new(RoomState { state: doc, active: false })) 
-            }
+        let doc = Doc::new();
+        doc.get_or_insert_map("shapes");
+        Self {
+            value: Arc::new(Mutex::new(RoomState {
+                state: doc,
+                last_used: Instant::now(),
+                size: 0,
+                unmeasured: 0,
+                hydrated: false,
+                persisted: false,
+            })),
+            activation: Arc::new(sync::Mutex::new(Activation::default())),
         }
     }
 
-    pub async fn activate(&self) {
+    /// Apply the persisted state of the room to its document
+    ///
+    /// Only a room fresh in memory is loaded, every later update of a resident room is already
+    /// applied to its document. An evicted room is dropped, so it is loaded again on its next
+    /// activation.
+    pub async fn hydrate(&self, store: Arc<dyn RoomStore>, room_id: &str) -> anyhow::Result<()> {
+        if self.value.lock().await.hydrated {
+            return Ok(());
+        }
+        let id = room_id.to_string();
+        // loading reads the whole room from storage, keep it off the runtime
+        let updates = tokio::task::spawn_blocking(move || store.load(&id)).await??;
         let mut room_state = self.value.lock().await;
-        room_state.active = true;
+        {
+            let mut txn = room_state.state.transact_mut();
+            for update in updates.iter() {
+                txn.apply_update(Update::decode_v1(update)?);
+            }
+        }
+        room_state.hydrated = true;
+        room_state.persisted = !updates.is_empty();
+        room_state.measure();
+        Ok(())
     }
 
-    pub async fn deactivate(&self) {
-        let mut room_state = self.value.lock().await;
-        room_state.active = false;
+    /// Activate the room, `None` if it is already active
+    ///
+    /// The room stays active until the returned guard is dropped, also when the session serving
+    /// it is dropped mid-await because its connection closed.
+    pub async fn try_activate(&self) -> Option<ActiveRoom> {
+        {
+            let mut activation = self.activation.lock().unwrap();
+            if activation.active {
+                return None;
+            }
+            activation.active = true;
+            activation.deactivated_at = None;
+        }
+        self.value.lock().await.last_used = Instant::now();
+        Some(ActiveRoom { room: self.clone() })
     }
 
-    pub async fn is_active(&self) -> bool {
-        let room_state = self.value.lock().await;
-        room_state.active
+    fn is_active(&self) -> bool {
+        self.activation.lock().unwrap().active
     }
 }
 
@@ -47,28 +149,240 @@ struct State {
     rooms: HashMap<String, Room>,
 }
 
+/// Memory usage of the rooms resident in memory
+pub struct RoomsUsage {
+    pub rooms: usize,
+    pub bytes: usize,
+}
+
 #[derive(Clone)]
 pub struct Rooms {
     value: Arc<Mutex<State>>,
+    store: Arc<dyn RoomStore>,
 }
 
 impl Rooms {
-    pub fn new() -> Self {
+    pub fn new(store: Arc<dyn RoomStore>) -> Self {
         Self {
             value: Arc::new(Mutex::new(State {
                 rooms: HashMap::new(),
             })),
+            store,
         }
     }
 
-    pub async fn get_room(&self, key: &String) -> Option<Room> {
-        let state = self.value.lock().await;
-        let room = state.rooms.get(key)?.clone();
-        Some(room)
+    pub fn store(&self) -> Arc<dyn RoomStore> {
+        self.store.clone()
     }
 
-    pub async fn insert(&self, room_id: String, room: Room) {
+    /// Get a room, inserting an empty one if it is not in memory yet
+    ///
+    /// Concurrent announces of the same room always end up with the same `Room`, its persisted
+    /// state is restored by the provider that activates it.
+    pub async fn get_or_insert(&self, room_id: &str) -> Room {
         let mut state = self.value.lock().await;
-        state.rooms.insert(room_id, room);
+        state
+            .rooms
+            .entry(room_id.to_string())
+            .or_insert_with(Room::new)
+            .clone()
+    }
+
+    /// Drop the rooms that have been deactivated for longer than `ttl`, returns their ids
+    ///
+    /// Every update is already in storage, so the next announce of an evicted room transparently
+    /// restores it.
+    pub async fn evict_idle(&self, ttl: Duration) -> Vec<String> {
+        let mut state = self.value.lock().await;
+        let mut evicted = Vec::new();
+        for (room_id, room) in state.rooms.iter() {
+            // a session serving this room still holds it
+            if Arc::strong_count(&room.value) > 1 {
+                continue;
+            }
+            let activation = room.activation.lock().unwrap();
+            let idle = match activation.deactivated_at {
+                Some(deactivated_at) => !activation.active && deactivated_at.elapsed() > ttl,
+                None => false,
+            };
+            if idle {
+                evicted.push(room_id.clone());
+            }
+        }
+        for room_id in evicted.iter() {
+            state.rooms.remove(room_id);
+        }
+        evicted
+    }
+
+    /// Drop the least recently used inactive rooms until the estimated size of all resident
+    /// documents fits in `budget` bytes, returns their ids
+    pub async fn evict_over_budget(&self, budget: usize) -> Vec<String> {
+        let mut total = 0;
+        let mut candidates = Vec::new();
+        for (room_id, room) in self.snapshot().await {
+            let room_state = room.value.lock().await;
+            total += room_state.size;
+            if !room.is_active() {
+                candidates.push((room_state.last_used, room_state.size, room_id));
+            }
+        }
+        candidates.sort();
+
+        let mut state = self.value.lock().await;
+        let mut evicted = Vec::new();
+        for (_, size, room_id) in candidates {
+            if total <= budget {
+                break;
+            }
+            // a session may have picked up the room while the sizes were read
+            let Some(room) = state.rooms.get(&room_id) else {
+                total -= size;
+                continue;
+            };
+            if room.is_active() || Arc::strong_count(&room.value) > 1 {
+                continue;
+            }
+            total -= size;
+            state.rooms.remove(&room_id);
+            evicted.push(room_id);
+        }
+        evicted
+    }
+
+    pub async fn usage(&self) -> RoomsUsage {
+        let rooms = self.snapshot().await;
+        let mut bytes = 0;
+        for (_, room) in rooms.iter() {
+            bytes += room.value.lock().await.size;
+        }
+        RoomsUsage {
+            rooms: rooms.len(),
+            bytes,
+        }
+    }
+
+    /// The rooms resident in memory, so their locks are awaited without holding the registry,
+    /// which every announce needs
+    async fn snapshot(&self) -> Vec<(String, Room)> {
+        let state = self.value.lock().await;
+        state
+            .rooms
+            .iter()
+            .map(|(room_id, room)| (room_id.clone(), room.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{room_store::shape_updates, sqlite_room_store::SqliteRoomStore};
+    use yrs::Map;
+
+    #[tokio::test]
+    async fn test_active_room_released_on_drop() {
+        let rooms = Rooms::new(Arc::new(SqliteRoomStore::in_memory()));
+        let room = rooms.get_or_insert("x").await;
+
+        // a session dropped mid-serve, as when its relay connection closes
+        let serve = async {
+            let _active = room.try_activate().await.unwrap();
+            assert!(room.try_activate().await.is_none());
+            std::future::pending::<()>().await
+        };
+        assert!(tokio::time::timeout(Duration::from_millis(10), serve)
+            .await
+            .is_err());
+
+        assert!(!room.is_active());
+        assert!(room.activation.lock().unwrap().deactivated_at.is_some());
+        assert!(room.try_activate().await.is_some());
+    }
+
+    #[test]
+    fn test_record_update() {
+        let room = Room::new();
+        let mut room_state = room.value.try_lock().unwrap();
+        room_state.record_update(100);
+        assert_eq!((room_state.size, room_state.unmeasured), (100, 100));
+
+        // measured once the unmeasured updates outweigh the measured size
+        room_state.record_update(MIN_UNMEASURED);
+        assert_eq!(room_state.unmeasured, 0);
+        assert!(room_state.size < 100);
+
+        room_state.size = 4 * MIN_UNMEASURED;
+        room_state.record_update(3 * MIN_UNMEASURED);
+        assert_eq!(room_state.unmeasured, 3 * MIN_UNMEASURED);
+        room_state.record_update(2 * MIN_UNMEASURED);
+        assert_eq!(room_state.unmeasured, 0);
+    }
+
+    #[tokio::test]
+    async fn test_record_persisted() {
+        let rooms = Rooms::new(Arc::new(SqliteRoomStore::in_memory()));
+        let room = rooms.get_or_insert("x").await;
+        room.hydrate(rooms.store(), "x").await.unwrap();
+        assert!(room.value.lock().await.record_persisted());
+        assert!(!room.value.lock().await.record_persisted());
+
+        // a room loaded from storage is already counted
+        rooms.store().append("y", &[0, 0]).unwrap();
+        let room = rooms.get_or_insert("y").await;
+        room.hydrate(rooms.store(), "y").await.unwrap();
+        assert!(!room.value.lock().await.record_persisted());
+    }
+
+    #[tokio::test]
+    async fn test_evict_over_budget() {
+        let rooms = Rooms::new(Arc::new(SqliteRoomStore::in_memory()));
+        let now = Instant::now();
+        for (i, room_id) in ["c", "a", "b", "d"].into_iter().enumerate() {
+            let room = rooms.get_or_insert(room_id).await;
+            let mut room_state = room.value.lock().await;
+            room_state.size = 10;
+            room_state.last_used = now + Duration::from_secs(i as u64);
+        }
+        // the least recently used room is active
+        let active = rooms.get_or_insert("c").await.try_activate().await.unwrap();
+
+        assert!(rooms.evict_over_budget(40).await.is_empty());
+        assert_eq!(
+            rooms.evict_over_budget(15).await,
+            vec!["a".to_string(), "b".to_string(), "d".to_string()]
+        );
+        assert_eq!(rooms.usage().await.bytes, 10);
+        drop(active);
+    }
+
+    #[tokio::test]
+    async fn test_evict_idle() {
+        let rooms = Rooms::new(Arc::new(SqliteRoomStore::in_memory()));
+        rooms.store().append("x", &shape_updates(1)[0]).unwrap();
+
+        // a room that was never activated is not idle
+        rooms.get_or_insert("never").await;
+        let room = rooms.get_or_insert("x").await;
+        room.hydrate(rooms.store(), "x").await.unwrap();
+        drop(room.try_activate().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(2)).await;
+
+        assert!(rooms.evict_idle(Duration::from_secs(60)).await.is_empty());
+        // a session still holds the room
+        assert!(rooms.evict_idle(Duration::ZERO).await.is_empty());
+        drop(room);
+        assert_eq!(
+            rooms.evict_idle(Duration::ZERO).await,
+            vec!["x".to_string()]
+        );
+        assert_eq!(rooms.usage().await.rooms, 1);
+
+        // the evicted room is restored from storage
+        let room = rooms.get_or_insert("x").await;
+        room.hydrate(rooms.store(), "x").await.unwrap();
+        let room_state = room.value.lock().await;
+        let shapes = room_state.state.get_or_insert_map("shapes");
+        assert_eq!(shapes.len(&room_state.state.transact()), 1);
     }
 }
@@ -10,11 +10,11 @@ use tokio::sync::Mutex;
 
 use crate::{
     config::Config,
+    fair_queue,
     room_announce_pattern::RoomAnnouncePattern,
-    room_listener::RoomListener,
-    room_packet::RoomPacket,
-    room_provider::RoomProvider,
-    rooms::{Room, Rooms},
+    room_listener::{ListenerOptions, RoomListener},
+    room_provider::{ProviderOptions, RoomProvider},
+    rooms::Rooms,
 };
 
 #[derive(Clone)]
@@ -25,11 +25,11 @@ pub struct Session {
 }
 
 impl Session {
-    pub fn new(session: web_transport::Session, config: Config) -> Self {
+    pub fn new(session: web_transport::Session, config: Config, rooms: Rooms) -> Self {
         Self {
             session,
             config,
-            rooms: Rooms::new(),
+            rooms,
         }
     }
 
@@ -83,40 +83,33 @@ impl Session {
         relay_subscriber: moq_transport::session::Subscriber,
         announce: Announced,
     ) -> anyhow::Result<()> {
-        //   - publish events received from publishers (every e.g. 1/60 sec tick)
         let namespace = announce.namespace.clone();
         let announce = RoomAnnouncePattern::parse_announce(
             self.config.index_namespace.clone(),
-            self.config.participant_prefix,
+            self.config.participant_prefix.clone(),
             namespace,
         );
 
         if let Some(announce) = announce {
-            let room = self.rooms.get_room(&announce.room_id).await;
-            let room = match room {
-                Some(room) => room,
-                None => {
-                    let room = Room::new();
-                    self.rooms
-                        .insert(announce.room_id.clone(), room.clone())
-                        .await;
-                    room
-                }
-            };
-            if room.is_active().await {
+            let room = self.rooms.get_or_insert(&announce.room_id).await;
+            let Some(active) = room.try_activate().await else {
+                // the same room is announced over another connection, which already serves it
+                log::info!("room {} is already served", announce.room_id);
                 return Ok(());
-            }
-            room.activate().await;
-            // TODO: make a scheduler from this: ForwardScheduler
-            let (sender, receiver) = tokio::sync::mpsc::channel::<RoomPacket>(1024);
+            };
+            // every participant gets a queue of its own, drained fairly by the provider
+            let (sender, receiver) = fair_queue::channel(1024);
+            let (left_sender, left_receiver) = tokio::sync::mpsc::unbounded_channel();
             let listener_announce = announce.clone();
             let room_listener = RoomListener::new(
                 relay_subscriber,
                 sender,
                 listener_announce,
-                self.config.track.clone(),
+                left_sender,
+                ListenerOptions::from_config(&self.config),
             );
 
+            let provider_options = ProviderOptions::from_config(&self.config, &announce.room_id);
             let uuid = uuid::Uuid::new_v4();
             let provider_announce = RoomAnnouncePattern::new(
                 self.config.index_namespace,
@@ -129,8 +122,10 @@ impl Session {
                 room.clone(),
                 relay_publisher,
                 receiver,
+                left_receiver,
                 provider_announce,
-                self.config.track,
+                self.rooms.store(),
+                provider_options,
             );
 
             let result = tokio::select! {
@@ -144,7 +139,7 @@ impl Session {
 
             // create provider, allow provider to write to some state we store in this struct
             // use select for room_listener and room_provider
-            room.deactivate().await;
+            drop(active);
         }
 
         Ok(())
@@ -0,0 +1,158 @@
+use crate::{
+    compression::Compression,
+    room_packet::{SnapshotChunkPacket, SnapshotPacket, UpdateEncoding},
+};
+
+/// Most chunks a single snapshot may be split into
+const MAX_CHUNKS: u32 = 4096;
+
+/// Reassembles the snapshots a publisher split into chunks
+///
+/// Chunks may arrive in any order. A publisher sends one snapshot at a time, so the chunks of
+/// an incomplete snapshot are dropped once a chunk of another snapshot arrives.
+pub struct SnapshotAssembler {
+    pending: Option<PendingSnapshot>,
+    /// Most bytes a reassembled snapshot may hold
+    max_size: usize,
+}
+
+struct PendingSnapshot {
+    snapshot_id: u64,
+    encoding: UpdateEncoding,
+    compression: Compression,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+}
+
+impl SnapshotAssembler {
+    pub fn new(max_size: usize) -> Self {
+        Self { pending: None, max_size }
+    }
+
+    /// Add a chunk, returns the snapshot once all of its chunks are received
+    pub fn push(&mut self, chunk: SnapshotChunkPacket) -> anyhow::Result<Option<SnapshotPacket>> {
+        if chunk.count > MAX_CHUNKS {
+            anyhow::bail!("snapshot {} has too many chunks {}", chunk.snapshot_id, chunk.count);
+        }
+        if chunk.index >= chunk.count {
+            anyhow::bail!(
+                "chunk {} of snapshot {} out of range {}",
+                chunk.index,
+                chunk.snapshot_id,
+                chunk.count
+            );
+        }
+
+        if self.pending.as_ref().map(|pending| pending.snapshot_id) != Some(chunk.snapshot_id) {
+            if let Some(pending) = &self.pending {
+                log::warn!(
+                    "dropping incomplete snapshot {} ({}/{} chunks)",
+                    pending.snapshot_id,
+                    pending.received,
+                    pending.chunks.len()
+                );
+            }
+            self.pending = Some(PendingSnapshot {
+                snapshot_id: chunk.snapshot_id,
+                encoding: chunk.encoding,
+                compression: chunk.compression,
+                chunks: vec![None; chunk.count as usize],
+                received: 0,
+                size: 0,
+            });
+        }
+        let pending = self.pending.as_mut().unwrap();
+        if pending.chunks.len() != chunk.count as usize
+            || pending.encoding != chunk.encoding
+            || pending.compression != chunk.compression
+        {
+            anyhow::bail!("chunk {} does not match snapshot {}", chunk.index, chunk.snapshot_id);
+        }
+
+        let slot = &mut pending.chunks[chunk.index as usize];
+        let size = pending.size - slot.as_ref().map(Vec::len).unwrap_or_default() + chunk.update.len();
+        if size > self.max_size {
+            let snapshot_id = pending.snapshot_id;
+            self.pending = None;
+            anyhow::bail!("snapshot {} exceeds {} bytes", snapshot_id, self.max_size);
+        }
+        if slot.is_none() {
+            pending.received += 1;
+        }
+        pending.size = size;
+        *slot = Some(chunk.update);
+        if pending.received < pending.chunks.len() {
+            return Ok(None);
+        }
+
+        let pending = self.pending.take().unwrap();
+        Ok(Some(SnapshotPacket {
+            update: pending.chunks.into_iter().flatten().flatten().collect(),
+            encoding: pending.encoding,
+            compression: pending.compression,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> SnapshotPacket {
+        SnapshotPacket {
+            update: (0..100).collect(),
+            encoding: UpdateEncoding::V1,
+            compression: Compression::Zstd,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_assembler_out_of_order() {
+        let mut chunks = snapshot().chunks(1, 4);
+        chunks.reverse();
+
+        let mut assembler = SnapshotAssembler::new(1024);
+        let last = chunks.pop().unwrap();
+        for chunk in chunks {
+            assert_eq!(assembler.push(chunk).unwrap(), None);
+        }
+        assert_eq!(assembler.push(last).unwrap(), Some(snapshot()));
+    }
+
+    #[test]
+    fn test_snapshot_assembler_drops_incomplete() {
+        let mut assembler = SnapshotAssembler::new(1024);
+        let first = snapshot().chunks(1, 2);
+        assert_eq!(assembler.push(first[0].clone()).unwrap(), None);
+
+        let second = snapshot().chunks(2, 2);
+        assert_eq!(assembler.push(second[0].clone()).unwrap(), None);
+        assert_eq!(assembler.push(second[1].clone()).unwrap(), Some(snapshot()));
+        assert_eq!(assembler.push(first[1].clone()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_snapshot_assembler_rejects_invalid() {
+        let mut assembler = SnapshotAssembler::new(1024);
+        let mut chunk = snapshot().chunks(1, 2).remove(0);
+        chunk.index = 2;
+        assert!(assembler.push(chunk.clone()).is_err());
+
+        chunk.index = 0;
+        assembler.push(chunk.clone()).unwrap();
+        chunk.count = 3;
+        assert!(assembler.push(chunk.clone()).is_err());
+
+        chunk.count = MAX_CHUNKS + 1;
+        assert!(assembler.push(chunk).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_assembler_max_size() {
+        let mut assembler = SnapshotAssembler::new(60);
+        let chunks = snapshot().chunks(1, 2);
+        assert_eq!(assembler.push(chunks[0].clone()).unwrap(), None);
+        assert!(assembler.push(chunks[1].clone()).is_err());
+    }
+}
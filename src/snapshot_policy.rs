@@ -0,0 +1,185 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::config::Config;
+
+/// When a provider cuts a new snapshot, whichever limit is reached first
+///
+/// A limit of zero disables it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnapshotPolicy {
+    /// Number of state packets since the last snapshot
+    pub deltas: usize,
+    /// Bytes of updates since the last snapshot
+    pub bytes: usize,
+    /// Time since the last snapshot, only when the document changed
+    pub interval: Duration,
+}
+
+impl SnapshotPolicy {
+    /// The global policy of the config, with the overrides for `room_id` applied
+    pub fn from_config(config: &Config, room_id: &str) -> Self {
+        let mut policy = Self {
+            deltas: config.snapshot_deltas,
+            bytes: config.snapshot_bytes,
+            interval: Duration::from_secs(config.snapshot_secs),
+        };
+        for room in config.room_snapshot_policy.iter() {
+            if room.room_id == room_id {
+                policy.deltas = room.deltas.unwrap_or(policy.deltas);
+                policy.bytes = room.bytes.unwrap_or(policy.bytes);
+                policy.interval = room.secs.map(Duration::from_secs).unwrap_or(policy.interval);
+            }
+        }
+        policy
+    }
+}
+
+/// Overrides of the snapshot policy for a single room
+///
+/// Parsed from `<room_id>=deltas:<n>,bytes:<n>,secs:<n>`, where every limit is optional.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomSnapshotPolicy {
+    pub room_id: String,
+    pub deltas: Option<usize>,
+    pub bytes: Option<usize>,
+    pub secs: Option<u64>,
+}
+
+impl FromStr for RoomSnapshotPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (room_id, limits) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected <room_id>=<limits>, got {:?}", value))?;
+        let mut policy = Self {
+            room_id: room_id.to_string(),
+            deltas: None,
+            bytes: None,
+            secs: None,
+        };
+        for limit in limits.split(',').filter(|l| !l.is_empty()) {
+            let (name, amount) = limit
+                .split_once(':')
+                .ok_or_else(|| format!("expected <limit>:<amount>, got {:?}", limit))?;
+            let invalid = |_| format!("invalid amount for {}: {:?}", name, amount);
+            match name {
+                "deltas" => policy.deltas = Some(amount.parse().map_err(invalid)?),
+                "bytes" => policy.bytes = Some(amount.parse().map_err(invalid)?),
+                "secs" => policy.secs = Some(amount.parse().map_err(invalid)?),
+                _ => return Err(format!("unknown snapshot limit {:?}", name)),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// Tracks the updates since the last snapshot against a `SnapshotPolicy`
+pub struct SnapshotCadence {
+    policy: SnapshotPolicy,
+    deltas: usize,
+    bytes: usize,
+    last_snapshot: Instant,
+}
+
+impl SnapshotCadence {
+    pub fn new(policy: SnapshotPolicy) -> Self {
+        Self {
+            policy,
+            deltas: 0,
+            bytes: 0,
+            last_snapshot: Instant::now(),
+        }
+    }
+
+    /// Account for a state packet carrying an update of `len` bytes
+    pub fn record(&mut self, len: usize) {
+        self.deltas += 1;
+        self.bytes += len;
+    }
+
+    /// When a snapshot becomes due by time alone, `None` if nothing changed since the last one
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.deltas == 0 || self.policy.interval.is_zero() {
+            return None;
+        }
+        Some(self.last_snapshot + self.policy.interval)
+    }
+
+    pub fn is_due(&self) -> bool {
+        let reached = |limit: usize, value: usize| limit > 0 && value >= limit;
+        reached(self.policy.deltas, self.deltas)
+            || reached(self.policy.bytes, self.bytes)
+            || self.deadline().is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    pub fn reset(&mut self) {
+        self.deltas = 0;
+        self.bytes = 0;
+        self.last_snapshot = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_room_snapshot_policy_parse() {
+        assert_eq!(
+            "abc=deltas:10,secs:5".parse::<RoomSnapshotPolicy>(),
+            Ok(RoomSnapshotPolicy {
+                room_id: "abc".to_string(),
+                deltas: Some(10),
+                bytes: None,
+                secs: Some(5),
+            })
+        );
+        assert_eq!(
+            "abc=".parse::<RoomSnapshotPolicy>(),
+            Ok(RoomSnapshotPolicy {
+                room_id: "abc".to_string(),
+                deltas: None,
+                bytes: None,
+                secs: None,
+            })
+        );
+        assert!("abc".parse::<RoomSnapshotPolicy>().is_err());
+        assert!("abc=deltas:x".parse::<RoomSnapshotPolicy>().is_err());
+        assert!("abc=frames:1".parse::<RoomSnapshotPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_snapshot_cadence() {
+        let mut cadence = SnapshotCadence::new(SnapshotPolicy {
+            deltas: 3,
+            bytes: 100,
+            interval: Duration::from_secs(3600),
+        });
+        assert!(!cadence.is_due());
+        assert_eq!(cadence.deadline(), None);
+
+        cadence.record(10);
+        cadence.record(10);
+        assert!(!cadence.is_due());
+        assert!(cadence.deadline().is_some());
+        cadence.record(10);
+        assert!(cadence.is_due());
+
+        cadence.reset();
+        cadence.record(100);
+        assert!(cadence.is_due());
+
+        let mut cadence = SnapshotCadence::new(SnapshotPolicy {
+            deltas: 0,
+            bytes: 0,
+            interval: Duration::ZERO,
+        });
+        cadence.record(1000);
+        assert!(!cadence.is_due());
+        assert_eq!(cadence.deadline(), None);
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    compression::Compression,
+    room_store::{merge_updates, RoomMetadata, RoomStore},
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS rooms (
+    room_id TEXT PRIMARY KEY,
+    snapshot BLOB,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS updates (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    room_id TEXT NOT NULL,
+    data BLOB NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS updates_room_id ON updates (room_id, id);
+";
+
+/// Stores all rooms in a single SQLite database
+///
+/// The `rooms` table holds the snapshot and timestamps of every room, the `updates` table the
+/// updates applied since that snapshot.
+pub struct SqliteRoomStore {
+    connection: Mutex<Connection>,
+    /// Compression of newly written snapshots
+    compression: Compression,
+}
+
+impl SqliteRoomStore {
+    pub fn open(path: &Path, compression: Compression) -> anyhow::Result<Self> {
+        let connection =
+            Connection::open(path).context(format!("failed to open database {:?}", path))?;
+        Self::from_connection(connection, compression)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        let connection = Connection::open_in_memory().unwrap();
+        Self::from_connection(connection, Compression::None).unwrap()
+    }
+
+    fn from_connection(connection: Connection, compression: Compression) -> anyhow::Result<Self> {
+        connection
+            .execute_batch(SCHEMA)
+            .context("failed to create database schema")?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            compression,
+        })
+    }
+
+    fn load_locked(connection: &Connection, room_id: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut updates = Vec::new();
+        let snapshot: Option<Option<Vec<u8>>> = connection
+            .query_row(
+                "SELECT snapshot FROM rooms WHERE room_id = ?1",
+                params![room_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(Some(snapshot)) = snapshot {
+            updates.push(Compression::unpack_snapshot(&snapshot)?);
+        }
+        let mut statement =
+            connection.prepare("SELECT data FROM updates WHERE room_id = ?1 ORDER BY id")?;
+        for update in statement.query_map(params![room_id], |row| row.get(0))? {
+            updates.push(update?);
+        }
+        Ok(updates)
+    }
+}
+
+impl RoomStore for SqliteRoomStore {
+    fn load(&self, room_id: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+        let connection = self.connection.lock().unwrap();
+        Self::load_locked(&connection, room_id)
+    }
+
+    fn append_batch(&self, room_id: &str, updates: &[Vec<u8>]) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let now = now_millis();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO rooms (room_id, snapshot, created_at, updated_at) VALUES (?1, NULL, ?2, ?2)
+             ON CONFLICT (room_id) DO UPDATE SET updated_at = excluded.updated_at",
+            params![room_id, now],
+        )?;
+        for update in updates {
+            transaction.execute(
+                "INSERT INTO updates (room_id, data, created_at) VALUES (?1, ?2, ?3)",
+                params![room_id, update, now],
+            )?;
+        }
+        transaction
+            .commit()
+            .context(format!("failed to append updates to room {}", room_id))
+    }
+
+    fn compact(&self, room_id: &str) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let snapshot = merge_updates(&Self::load_locked(&transaction, room_id)?)?;
+        let snapshot = self.compression.pack(&snapshot)?;
+        transaction.execute(
+            "UPDATE rooms SET snapshot = ?2 WHERE room_id = ?1",
+            params![room_id, snapshot],
+        )?;
+        transaction.execute("DELETE FROM updates WHERE room_id = ?1", params![room_id])?;
+        transaction
+            .commit()
+            .context(format!("failed to compact room {}", room_id))
+    }
+
+    fn uncompacted(&self) -> anyhow::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT DISTINCT room_id FROM updates")?;
+        let room_ids = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(room_ids)
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT room_id FROM rooms ORDER BY room_id")?;
+        let room_ids = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(room_ids)
+    }
+
+    fn delete(&self, room_id: &str) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM updates WHERE room_id = ?1", params![room_id])?;
+        transaction.execute("DELETE FROM rooms WHERE room_id = ?1", params![room_id])?;
+        transaction
+            .commit()
+            .context(format!("failed to delete room {}", room_id))
+    }
+
+    fn metadata(&self, room_id: &str) -> anyhow::Result<Option<RoomMetadata>> {
+        let connection = self.connection.lock().unwrap();
+        let metadata = connection
+            .query_row(
+                "SELECT created_at, updated_at, COALESCE(LENGTH(snapshot), 0)
+                    + (SELECT COALESCE(SUM(LENGTH(data)), 0) FROM updates WHERE room_id = ?1)
+                 FROM rooms WHERE room_id = ?1",
+                params![room_id],
+                |row| {
+                    Ok(RoomMetadata {
+                        room_id: room_id.to_string(),
+                        created_at: from_millis(row.get(0)?),
+                        updated_at: from_millis(row.get(1)?),
+                        size: row.get::<_, i64>(2)? as u64,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(metadata)
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_store::shape_updates;
+
+    #[test]
+    fn test_sqlite_room_store() {
+        let connection = Connection::open_in_memory().unwrap();
+        let store = SqliteRoomStore::from_connection(connection, Compression::Deflate).unwrap();
+
+        assert!(store.load("x").unwrap().is_empty());
+        assert_eq!(store.metadata("x").unwrap(), None);
+
+        let update = shape_updates(1).remove(0);
+        store.append("x", &update).unwrap();
+        store.append("x", &update).unwrap();
+        assert_eq!(
+            store.load("x").unwrap(),
+            vec![update.clone(), update.clone()]
+        );
+        assert_eq!(store.uncompacted().unwrap(), vec!["x".to_string()]);
+        assert_eq!(
+            store.metadata("x").unwrap().unwrap().size,
+            2 * update.len() as u64
+        );
+
+        store.compact("x").unwrap();
+        assert!(store.uncompacted().unwrap().is_empty());
+        assert_eq!(store.load("x").unwrap().len(), 1);
+        assert_eq!(store.list().unwrap(), vec!["x".to_string()]);
+
+        store.delete("x").unwrap();
+        assert!(store.list().unwrap().is_empty());
+        assert!(store.load("x").unwrap().is_empty());
+    }
+}
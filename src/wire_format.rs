@@ -0,0 +1,476 @@
+use anyhow::Context;
+use serde_json::Value;
+
+use crate::{
+    compression::Compression,
+    room_packet::{
+        AwarenessPacket, DeltaPacket, RoomPacket, SnapshotChunkPacket, SnapshotPacket, StatePacket,
+        SyncRequestPacket, SyncResponsePacket, UpdateEncoding,
+    },
+};
+
+/// First byte of a binary object, JSON never starts with it
+const BINARY_MAGIC: u8 = 0x00;
+const BINARY_VERSION: u8 = 1;
+
+const TAG_SNAPSHOT: u8 = 1;
+const TAG_DELTA: u8 = 2;
+const TAG_OTHER: u8 = 3;
+const TAG_SNAPSHOT_V2: u8 = 4;
+const TAG_DELTA_V2: u8 = 5;
+/// A compressed snapshot, the update is preceded by its encoding and compression byte
+const TAG_SNAPSHOT_COMPRESSED: u8 = 7;
+/// A snapshot chunk: encoding and compression byte, LEB128 snapshot id, index and count,
+/// followed by the part of the update
+const TAG_SNAPSHOT_CHUNK: u8 = 8;
+/// An awareness packet: LEB128 length and the publisher id, followed by the awareness update
+const TAG_AWARENESS: u8 = 9;
+/// A sync request: LEB128 length and the publisher id, followed by the state vector
+const TAG_SYNC_REQUEST: u8 = 10;
+/// A sync response: encoding byte, LEB128 length and the publisher id, followed by the update
+const TAG_SYNC_RESPONSE: u8 = 11;
+
+/// Format of the packets on a provider track, subscribers pick a format by subscribing to the
+/// matching track: `<track>[.bin][.v2]`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrackFormat {
+    pub wire: WireFormat,
+    pub encoding: UpdateEncoding,
+}
+
+impl TrackFormat {
+    pub fn all() -> Vec<Self> {
+        let mut formats = Vec::new();
+        for wire in WireFormat::ALL {
+            for encoding in UpdateEncoding::ALL {
+                formats.push(Self { wire, encoding });
+            }
+        }
+        formats
+    }
+
+    pub fn track_name(&self, track: &str) -> String {
+        let mut name = track.to_string();
+        if self.wire == WireFormat::Binary {
+            name.push_str(".bin");
+        }
+        if self.encoding == UpdateEncoding::V2 {
+            name.push_str(".v2");
+        }
+        name
+    }
+}
+
+impl WireFormat {
+    /// Ephemeral packets have a track of their own per wire format: `<track>[.bin].ephemeral`
+    pub fn ephemeral_track_name(self, track: &str) -> String {
+        let format = TrackFormat {
+            wire: self,
+            encoding: UpdateEncoding::V1,
+        };
+        format!("{}.ephemeral", format.track_name(track))
+    }
+
+    /// Name of the track carrying the sync responses of a single participant
+    pub fn sync_track_name(self, track: &str, publisher_id: &str) -> String {
+        let format = TrackFormat {
+            wire: self,
+            encoding: UpdateEncoding::V1,
+        };
+        format!("{}.sync.{}", format.track_name(track), publisher_id)
+    }
+}
+
+/// Encoding of the packets in a single object
+///
+/// - `Json`: a JSON array of packets, every update is an array of numbers
+/// - `Binary`: `BINARY_MAGIC`, `BINARY_VERSION`, followed by frames of a tag byte, a LEB128
+///   length and the payload: the raw yrs update, or the JSON of other packets. The tag also
+///   carries the encoding of the update.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    Binary,
+}
+
+impl WireFormat {
+    pub const ALL: [WireFormat; 2] = [WireFormat::Json, WireFormat::Binary];
+
+    pub fn encode(self, packets: &[RoomPacket]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            WireFormat::Json => {
+                let values = packets
+                    .iter()
+                    .map(|packet| match packet {
+                        RoomPacket::StatePacket(packet) => serde_json::to_value(packet),
+                        RoomPacket::Awareness(packet) => Ok(packet.to_json()),
+                        RoomPacket::Other(v) => Ok(v.clone()),
+                    })
+                    .collect::<Result<Vec<Value>, _>>()?;
+                Ok(serde_json::to_vec(&values)?)
+            }
+            WireFormat::Binary => {
+                let mut payload = vec![BINARY_MAGIC, BINARY_VERSION];
+                for packet in packets {
+                    match packet {
+                        RoomPacket::StatePacket(StatePacket::DocSnapshot(packet))
+                            if !packet.compression.is_none() =>
+                        {
+                            let mut frame = vec![
+                                encoding_byte(packet.encoding),
+                                packet.compression.to_byte(),
+                            ];
+                            frame.extend_from_slice(&packet.update);
+                            write_frame(&mut payload, TAG_SNAPSHOT_COMPRESSED, &frame)
+                        }
+                        RoomPacket::StatePacket(StatePacket::DocSnapshotChunk(packet)) => {
+                            let mut frame = vec![
+                                encoding_byte(packet.encoding),
+                                packet.compression.to_byte(),
+                            ];
+                            write_varint(&mut frame, packet.snapshot_id as usize);
+                            write_varint(&mut frame, packet.index as usize);
+                            write_varint(&mut frame, packet.count as usize);
+                            frame.extend_from_slice(&packet.update);
+                            write_frame(&mut payload, TAG_SNAPSHOT_CHUNK, &frame)
+                        }
+                        RoomPacket::StatePacket(StatePacket::DocSyncRequest(packet)) => {
+                            let mut frame = Vec::new();
+                            write_string(&mut frame, &packet.publisher_id);
+                            frame.extend_from_slice(&packet.state_vector);
+                            write_frame(&mut payload, TAG_SYNC_REQUEST, &frame)
+                        }
+                        RoomPacket::StatePacket(StatePacket::DocSyncResponse(packet)) => {
+                            let mut frame = vec![encoding_byte(packet.encoding)];
+                            write_string(&mut frame, &packet.publisher_id);
+                            frame.extend_from_slice(&packet.update);
+                            write_frame(&mut payload, TAG_SYNC_RESPONSE, &frame)
+                        }
+                        RoomPacket::StatePacket(StatePacket::DocSnapshot(packet)) => {
+                            let tag = match packet.encoding {
+                                UpdateEncoding::V1 => TAG_SNAPSHOT,
+                                UpdateEncoding::V2 => TAG_SNAPSHOT_V2,
+                            };
+                            write_frame(&mut payload, tag, &packet.update)
+                        }
+                        RoomPacket::StatePacket(StatePacket::DocDelta(packet)) => {
+                            let tag = match packet.encoding {
+                                UpdateEncoding::V1 => TAG_DELTA,
+                                UpdateEncoding::V2 => TAG_DELTA_V2,
+                            };
+                            write_frame(&mut payload, tag, &packet.update)
+                        }
+                        RoomPacket::Awareness(packet) => {
+                            let mut frame = Vec::new();
+                            write_string(&mut frame, &packet.publisher_id);
+                            frame.extend_from_slice(&packet.update);
+                            write_frame(&mut payload, TAG_AWARENESS, &frame)
+                        }
+                        RoomPacket::Other(v) => {
+                            write_frame(&mut payload, TAG_OTHER, &serde_json::to_vec(v)?)
+                        }
+                    }
+                }
+                Ok(payload)
+            }
+        }
+    }
+
+    /// Decode an object in either format
+    pub fn decode(payload: &[u8]) -> anyhow::Result<Vec<RoomPacket>> {
+        match payload.first() {
+            Some(&BINARY_MAGIC) => decode_binary(payload),
+            _ => decode_json(payload),
+        }
+    }
+}
+
+fn decode_json(payload: &[u8]) -> anyhow::Result<Vec<RoomPacket>> {
+    let values: Vec<Value> = serde_json::from_slice(payload)?;
+    let packets = values
+        .into_iter()
+        .map(|value| match serde_json::from_value::<StatePacket>(value.clone()) {
+            Ok(packet) => RoomPacket::StatePacket(packet),
+            Err(_) => match AwarenessPacket::from_json(&value) {
+                Some(packet) => RoomPacket::Awareness(packet),
+                None => RoomPacket::Other(value),
+            },
+        })
+        .collect();
+    Ok(packets)
+}
+
+fn decode_binary(payload: &[u8]) -> anyhow::Result<Vec<RoomPacket>> {
+    let version = *payload.get(1).context("missing binary format version")?;
+    if version != BINARY_VERSION {
+        anyhow::bail!("unsupported binary format version {}", version);
+    }
+    let mut rest = &payload[2..];
+    let mut packets = Vec::new();
+    while let Some((&tag, tail)) = rest.split_first() {
+        let (len, tail) = read_varint(tail)?;
+        let frame = tail.get(..len).context("truncated frame")?;
+        rest = &tail[len..];
+
+        let update = frame.to_vec();
+        let packet = match tag {
+            TAG_SNAPSHOT | TAG_SNAPSHOT_V2 => StatePacket::DocSnapshot(SnapshotPacket {
+                update,
+                encoding: tag_encoding(tag),
+                compression: Compression::None,
+            }),
+            TAG_SNAPSHOT_COMPRESSED => match frame {
+                [encoding, compression, update @ ..] => StatePacket::DocSnapshot(SnapshotPacket {
+                    update: update.to_vec(),
+                    encoding: byte_encoding(*encoding)?,
+                    compression: Compression::from_byte(*compression)?,
+                }),
+                _ => anyhow::bail!("truncated compressed snapshot"),
+            },
+            TAG_SNAPSHOT_CHUNK => match frame {
+                [encoding, compression, rest @ ..] => {
+                    let (snapshot_id, rest) = read_varint(rest)?;
+                    let (index, rest) = read_varint(rest)?;
+                    let (count, rest) = read_varint(rest)?;
+                    StatePacket::DocSnapshotChunk(SnapshotChunkPacket {
+                        snapshot_id: snapshot_id as u64,
+                        index: index.try_into()?,
+                        count: count.try_into()?,
+                        update: rest.to_vec(),
+                        encoding: byte_encoding(*encoding)?,
+                        compression: Compression::from_byte(*compression)?,
+                    })
+                }
+                _ => anyhow::bail!("truncated snapshot chunk"),
+            },
+            TAG_DELTA | TAG_DELTA_V2 => StatePacket::DocDelta(DeltaPacket {
+                update,
+                encoding: tag_encoding(tag),
+            }),
+            TAG_OTHER => {
+                packets.push(RoomPacket::Other(serde_json::from_slice(frame)?));
+                continue;
+            }
+            TAG_AWARENESS => {
+                let (publisher_id, rest) = read_string(frame)?;
+                packets.push(RoomPacket::Awareness(AwarenessPacket {
+                    publisher_id,
+                    update: rest.to_vec(),
+                }));
+                continue;
+            }
+            TAG_SYNC_REQUEST => {
+                let (publisher_id, rest) = read_string(frame)?;
+                StatePacket::DocSyncRequest(SyncRequestPacket {
+                    state_vector: rest.to_vec(),
+                    publisher_id,
+                })
+            }
+            TAG_SYNC_RESPONSE => match frame {
+                [encoding, rest @ ..] => {
+                    let (publisher_id, rest) = read_string(rest)?;
+                    StatePacket::DocSyncResponse(SyncResponsePacket {
+                        publisher_id,
+                        update: rest.to_vec(),
+                        encoding: byte_encoding(*encoding)?,
+                    })
+                }
+                _ => anyhow::bail!("truncated sync response"),
+            },
+            _ => anyhow::bail!("unknown frame tag {}", tag),
+        };
+        packets.push(RoomPacket::StatePacket(packet));
+    }
+    Ok(packets)
+}
+
+fn encoding_byte(encoding: UpdateEncoding) -> u8 {
+    match encoding {
+        UpdateEncoding::V1 => 1,
+        UpdateEncoding::V2 => 2,
+    }
+}
+
+fn byte_encoding(byte: u8) -> anyhow::Result<UpdateEncoding> {
+    match byte {
+        1 => Ok(UpdateEncoding::V1),
+        2 => Ok(UpdateEncoding::V2),
+        _ => anyhow::bail!("unknown update encoding {}", byte),
+    }
+}
+
+fn tag_encoding(tag: u8) -> UpdateEncoding {
+    match tag {
+        TAG_SNAPSHOT_V2 | TAG_DELTA_V2 => UpdateEncoding::V2,
+        _ => UpdateEncoding::V1,
+    }
+}
+
+fn write_frame(payload: &mut Vec<u8>, tag: u8, frame: &[u8]) {
+    payload.push(tag);
+    write_varint(payload, frame.len());
+    payload.extend_from_slice(frame);
+}
+
+/// Write a LEB128 length followed by the string
+fn write_string(payload: &mut Vec<u8>, value: &str) {
+    write_varint(payload, value.len());
+    payload.extend_from_slice(value.as_bytes());
+}
+
+fn read_string(bytes: &[u8]) -> anyhow::Result<(String, &[u8])> {
+    let (len, rest) = read_varint(bytes)?;
+    let value = rest.get(..len).context("truncated string")?;
+    Ok((String::from_utf8(value.to_vec())?, &rest[len..]))
+}
+
+/// Write `value` as LEB128, the varuint of lib0
+pub fn write_varint(payload: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            payload.push(byte);
+            break;
+        }
+        payload.push(byte | 0x80);
+    }
+}
+
+pub fn read_varint(mut bytes: &[u8]) -> anyhow::Result<(usize, &[u8])> {
+    let mut value: usize = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, tail) = bytes.split_first().context("truncated length")?;
+        bytes = tail;
+        // the last byte may only carry the bits that are left
+        let bits = (byte & 0x7f) as usize;
+        if shift >= usize::BITS || (bits << shift) >> shift != bits {
+            anyhow::bail!("length overflows");
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value, bytes));
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets() -> Vec<RoomPacket> {
+        vec![
+            RoomPacket::StatePacket(StatePacket::DocSnapshot(SnapshotPacket {
+                update: vec![1, 2, 3],
+                encoding: UpdateEncoding::V1,
+                compression: Compression::None,
+            })),
+            RoomPacket::StatePacket(StatePacket::DocSnapshot(SnapshotPacket {
+                update: vec![7, 8],
+                encoding: UpdateEncoding::V2,
+                compression: Compression::Deflate,
+            })),
+            RoomPacket::StatePacket(StatePacket::DocSnapshotChunk(SnapshotChunkPacket {
+                snapshot_id: 300,
+                index: 1,
+                count: 2,
+                update: vec![9, 9],
+                encoding: UpdateEncoding::V2,
+                compression: Compression::Zstd,
+            })),
+            RoomPacket::StatePacket(StatePacket::DocDelta(DeltaPacket {
+                update: (0..=255).collect(),
+                encoding: UpdateEncoding::V1,
+            })),
+            RoomPacket::StatePacket(StatePacket::DocDelta(DeltaPacket {
+                update: vec![4, 5],
+                encoding: UpdateEncoding::V2,
+            })),
+            RoomPacket::StatePacket(StatePacket::DocSyncRequest(SyncRequestPacket {
+                state_vector: vec![1, 5, 2],
+                publisher_id: "c".to_string(),
+            })),
+            RoomPacket::StatePacket(StatePacket::DocSyncResponse(SyncResponsePacket {
+                publisher_id: "a".to_string(),
+                update: vec![6],
+                encoding: UpdateEncoding::V2,
+            })),
+            RoomPacket::Awareness(AwarenessPacket {
+                publisher_id: "b".to_string(),
+                update: vec![1, 2, 3],
+            }),
+            RoomPacket::Other(serde_json::json!({"cursor": [1, 2]})),
+        ]
+    }
+
+    #[test]
+    fn test_wire_format_roundtrip() {
+        for format in WireFormat::ALL {
+            let payload = format.encode(&packets()).unwrap();
+            assert_eq!(WireFormat::decode(&payload).unwrap(), packets());
+        }
+    }
+
+    #[test]
+    fn test_track_format_names() {
+        let names: Vec<String> = TrackFormat::all()
+            .iter()
+            .map(|format| format.track_name(".doc"))
+            .collect();
+        assert_eq!(names, vec![".doc", ".doc.v2", ".doc.bin", ".doc.bin.v2"]);
+
+        let names: Vec<String> = WireFormat::ALL
+            .iter()
+            .map(|wire| wire.ephemeral_track_name(".doc"))
+            .collect();
+        assert_eq!(names, vec![".doc.ephemeral", ".doc.bin.ephemeral"]);
+        let names: Vec<String> = WireFormat::ALL
+            .into_iter()
+            .map(|wire| wire.sync_track_name(".doc", "a"))
+            .collect();
+        assert_eq!(names, vec![".doc.sync.a", ".doc.bin.sync.a"]);
+    }
+
+    #[test]
+    fn test_wire_format_binary_is_smaller() {
+        let json = WireFormat::Json.encode(&packets()).unwrap();
+        let binary = WireFormat::Binary.encode(&packets()).unwrap();
+        assert!(binary.len() * 2 < json.len());
+    }
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 127, 128, 300, usize::MAX] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value);
+            assert_eq!(read_varint(&bytes).unwrap(), (value, &[][..]));
+        }
+
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, usize::MAX);
+        *bytes.last_mut().unwrap() += 1;
+        assert!(read_varint(&bytes).is_err());
+        assert!(read_varint(&[0x80; 11]).is_err());
+        assert!(read_varint(&[0x80]).is_err());
+    }
+
+    #[test]
+    fn test_wire_format_decode_errors() {
+        assert!(WireFormat::decode(&[BINARY_MAGIC]).is_err());
+        assert!(WireFormat::decode(&[BINARY_MAGIC, 2]).is_err());
+        assert!(WireFormat::decode(&[BINARY_MAGIC, BINARY_VERSION, TAG_DELTA, 5, 1]).is_err());
+        // unassigned tag
+        assert!(WireFormat::decode(&[BINARY_MAGIC, BINARY_VERSION, 0xff, 0]).is_err());
+        // awareness frame shorter than its publisher id
+        assert!(
+            WireFormat::decode(&[BINARY_MAGIC, BINARY_VERSION, TAG_AWARENESS, 2, 5, b'a']).is_err()
+        );
+        assert!(WireFormat::decode(b"{").is_err());
+        assert_eq!(
+            WireFormat::decode(&[BINARY_MAGIC, BINARY_VERSION]).unwrap(),
+            vec![]
+        );
+    }
+}